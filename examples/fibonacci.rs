@@ -2,8 +2,7 @@ use brainfeed::Context;
 
 fn main() {
     let nth_fib = 7;
-    let mut code = String::new();
-    let mut ctx = Context::new(&mut code);
+    let mut ctx = Context::owned();
 
     ctx.with_stack_alloc4(|ctx, current, next, i, tmp| {
         ctx.increment_by(next, 1);
@@ -18,5 +17,5 @@ fn main() {
         ctx.clear(next);
     });
 
-    println!("{}", code);
+    println!("{}", ctx.finish());
 }
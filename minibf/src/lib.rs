@@ -1,105 +1,533 @@
 
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead};
+
 const MAX_STEPS: usize = 1_000_000;
 const MEM_SIZE: usize = 30_000;
 
-pub struct VM {
-    mem: [u8; MEM_SIZE],
-    loop_stack: Vec<usize>,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunOutcome {
+    Completed,
+    OutOfGas,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunError {
+    /// The `[` at this position is never closed.
+    UnmatchedOpen(usize),
+    /// The `]` at this position has no `[` to close.
+    UnmatchedClose(usize),
+    StepLimitExceeded,
+    /// A growable tape was moved left of its first cell.
+    TapeUnderflow,
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::UnmatchedOpen(position) => write!(f, "unmatched '[' at {}", position),
+            RunError::UnmatchedClose(position) => write!(f, "unmatched ']' at {}", position),
+            RunError::StepLimitExceeded => write!(f, "exceeded the step limit"),
+            RunError::TapeUnderflow => write!(f, "moved left of the first cell"),
+        }
+    }
+}
+
+impl Error for RunError {}
+
+/// Why `run_until_breakpoint` returned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    /// Execution is paused before the instruction at this position.
+    Breakpoint(usize),
+    Finished,
+}
+
+/// How many instructions of each kind the last run executed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    /// `<` and `>`
+    pub moves: u64,
+    /// `+` and `-`
+    pub mutations: u64,
+    /// `[` and `]`
+    pub loops: u64,
+    /// `.` and `,`
+    pub io: u64,
+}
+
+/// What `,` does once the input is used up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EofBehavior {
+    Zero,
+    MinusOne,
+    Unchanged,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VMConfig {
+    /// Number of cells on the tape. Must be at least 1.
+    pub mem_size: usize,
+    /// Number of steps after which `run` gives up.
+    pub max_steps: usize,
+    /// Grow the tape when moving right past its end instead of wrapping around.
+    /// Moving left of the first cell is an error then.
+    pub growable: bool,
+}
+
+impl Default for VMConfig {
+    fn default() -> Self {
+        Self {
+            mem_size: MEM_SIZE,
+            max_steps: MAX_STEPS,
+            growable: false,
+        }
+    }
+}
+
+/// A value that fits in one cell of the tape.
+pub trait Cell: Copy + PartialEq + fmt::Debug {
+    const ZERO: Self;
+    const ONE: Self;
+    const MAX: Self;
+
+    fn wrapping_add(self, other: Self) -> Self;
+    fn wrapping_sub(self, other: Self) -> Self;
+    /// Widens a byte read by `,`.
+    fn from_byte(byte: u8) -> Self;
+    /// Truncates the cell to the byte printed by `.`.
+    fn to_byte(self) -> u8;
+}
+
+macro_rules! impl_cell {
+    ($($ty:ty),*) => {$(
+        impl Cell for $ty {
+            const ZERO: Self = 0;
+            const ONE: Self = 1;
+            const MAX: Self = <$ty>::MAX;
+
+            fn wrapping_add(self, other: Self) -> Self {
+                <$ty>::wrapping_add(self, other)
+            }
+
+            fn wrapping_sub(self, other: Self) -> Self {
+                <$ty>::wrapping_sub(self, other)
+            }
+
+            fn from_byte(byte: u8) -> Self {
+                byte.into()
+            }
+
+            fn to_byte(self) -> u8 {
+                self as u8
+            }
+        }
+    )*};
+}
+
+impl_cell!(u8, u16, u32);
+
+pub struct VM<T: Cell = u8> {
+    mem: Vec<T>,
+    max_steps: usize,
+    growable: bool,
+    /// Address and length of the code passed to the last `load`.
+    loaded: (usize, usize),
+    /// For every bracket of the loaded code, the position of its partner.
+    jump_table: Vec<usize>,
     ip: usize,
     dp: usize,
+    /// Highest `dp` of the current run.
+    max_dp: usize,
     op_count: usize,
+    output: Vec<u8>,
+    input: VecDeque<u8>,
+    eof_behavior: EofBehavior,
+    breakpoints: HashSet<usize>,
+    profile: Profile,
 }
 
 impl VM {
     pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<T: Cell> VM<T> {
+    /// Panics if `config.mem_size` is 0.
+    pub fn with_config(config: VMConfig) -> Self {
+        assert!(config.mem_size > 0, "the tape needs at least one cell");
+
         Self {
-            mem: [0; MEM_SIZE],
-            loop_stack: Vec::new(),
+            mem: vec![T::ZERO; config.mem_size],
+            max_steps: config.max_steps,
+            growable: config.growable,
+            loaded: (0, 0),
+            jump_table: Vec::new(),
             ip: 0,
             dp: 0,
+            max_dp: 0,
             op_count: 0,
+            output: Vec::new(),
+            input: VecDeque::new(),
+            eof_behavior: EofBehavior::Zero,
+            breakpoints: HashSet::new(),
+            profile: Profile::default(),
         }
     }
-    pub fn run<C: AsRef<[u8]>>(&mut self, code: C) {
+
+    pub fn run<C: AsRef<[u8]>>(&mut self, code: C) -> Result<(), RunError> {
         let code = code.as_ref();
+        self.load(code)?;
+
+        loop {
+            let finished = self.step(code)?;
+
+            if self.op_count > self.max_steps {
+                return Err(RunError::StepLimitExceeded);
+            }
+
+            if finished {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs `code` on a cleared VM with the given input and returns what it printed.
+    pub fn run_with_input<C, I>(&mut self, code: C, input: I) -> Result<Vec<u8>, RunError>
+    where
+        C: AsRef<[u8]>,
+        I: Into<Vec<u8>>,
+    {
+        self.reset();
+        self.set_input(input);
+        self.run(code)?;
+
+        Ok(self.output.clone())
+    }
+
+    /// Clears the tape, the input and the output and moves back to the first cell.
+    /// Configuration and breakpoints are kept.
+    pub fn reset(&mut self) {
+        for cell in &mut self.mem {
+            *cell = T::ZERO;
+        }
+
+        self.dp = 0;
+        self.max_dp = 0;
         self.ip = 0;
         self.op_count = 0;
+        self.input.clear();
+        self.output.clear();
+    }
+
+    /// Executes a single instruction of `code` and returns whether the program is finished.
+    /// If `code` is not the code that was loaded last, it is loaded first and stepping starts at its beginning.
+    /// Code is told apart by its address and length, so code that was changed in place has to be `load`ed again.
+    pub fn step(&mut self, code: &[u8]) -> Result<bool, RunError> {
+        self.ensure_loaded(code)?;
+
+        if self.ip < code.len() {
+            self.exec(code)?;
+            self.op_count += 1;
+        }
+
+        Ok(self.ip >= code.len())
+    }
+
+    /// Pauses `run_until_breakpoint` before the instruction at `ip` is executed.
+    pub fn add_breakpoint(&mut self, ip: usize) {
+        self.breakpoints.insert(ip);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Runs `code` until the next breakpoint or until it is finished.
+    /// The instruction at the current ip is always executed,
+    /// so calling this again continues past the breakpoint it stopped at.
+    pub fn run_until_breakpoint(&mut self, code: &[u8]) -> Result<Stop, RunError> {
+        loop {
+            if self.step(code)? {
+                return Ok(Stop::Finished);
+            }
+
+            if self.op_count > self.max_steps {
+                return Err(RunError::StepLimitExceeded);
+            }
+
+            if self.breakpoints.contains(&self.ip) {
+                return Ok(Stop::Breakpoint(self.ip));
+            }
+        }
+    }
+
+    /// Runs `code` until it completes or has executed `gas` bytes of code.
+    /// Returns how the run ended and the gas that is left.
+    pub fn run_with_gas<C: AsRef<[u8]>>(&mut self, code: C, gas: u64) -> Result<(RunOutcome, u64), RunError> {
+        let code = code.as_ref();
+        self.load(code)?;
+        self.resume_with_gas(code, gas)
+    }
+
+    /// Continues a run of the same code that ran out of gas where it stopped.
+    /// Code other than the code that was loaded last, told apart like in `step`, is loaded and run from the start.
+    pub fn resume_with_gas<C: AsRef<[u8]>>(&mut self, code: C, mut gas: u64) -> Result<(RunOutcome, u64), RunError> {
+        let code = code.as_ref();
+        self.ensure_loaded(code)?;
 
         while self.ip < code.len() {
-            match code[self.ip] {
-                b'<' => self.left(),
-                b'>' => self.right(),
-                b'+' => self.increment(),
-                b'-' => self.decrement(),
-                b'[' => self.loop_start(code),
-                b']' => self.loop_end(),
-                b'.' => unimplemented!("op: ."),
-                b',' => unimplemented!("op: ,"),
-                _ => {}
+            if gas == 0 {
+                return Ok((RunOutcome::OutOfGas, 0));
             }
 
-            self.op_count += 1;
-            assert!(self.op_count <= MAX_STEPS);
+            self.exec(code)?;
+            gas -= 1;
+        }
+
+        Ok((RunOutcome::Completed, gas))
+    }
+
+    /// Prepares `code` to be run from the start, keeping the tape.
+    pub fn load(&mut self, code: &[u8]) -> Result<(), RunError> {
+        self.jump_table = jump_table(code)?;
+        self.loaded = code_key(code);
+        self.ip = 0;
+        self.op_count = 0;
+        self.output.clear();
+        self.profile = Profile::default();
+        self.max_dp = self.dp;
+
+        Ok(())
+    }
+
+    fn ensure_loaded(&mut self, code: &[u8]) -> Result<(), RunError> {
+        if self.loaded != code_key(code) {
+            self.load(code)?;
+        }
+
+        Ok(())
+    }
+
+    fn exec(&mut self, code: &[u8]) -> Result<(), RunError> {
+        let counter = match code[self.ip] {
+            b'<' | b'>' => Some(&mut self.profile.moves),
+            b'+' | b'-' => Some(&mut self.profile.mutations),
+            b'[' | b']' => Some(&mut self.profile.loops),
+            b'.' | b',' => Some(&mut self.profile.io),
+            _ => None,
+        };
+
+        if let Some(counter) = counter {
+            *counter += 1;
+        }
+
+        match code[self.ip] {
+            b'<' => self.left()?,
+            b'>' => self.right(),
+            b'+' => self.increment(),
+            b'-' => self.decrement(),
+            b'[' => self.loop_start(),
+            b']' => self.loop_end(),
+            b'.' => self.print(),
+            b',' => self.read(),
+            _ => self.ip += 1,
         }
+
+        Ok(())
+    }
+
+    /// Runs a program read from `src`.
+    /// Only the eight commands are kept in memory; everything else is dropped while reading.
+    pub fn run_reader<R: BufRead>(&mut self, mut src: R) -> io::Result<()> {
+        let mut code = Vec::new();
+
+        loop {
+            let buf = src.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+
+            code.extend(buf.iter().filter(|op| b"<>+-[].,".contains(op)));
+
+            let len = buf.len();
+            src.consume(len);
+        }
+
+        self.run(code).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Replaces the bytes that `,` reads.
+    /// What reading past the end does is set with `set_eof_behavior`.
+    pub fn set_input<I: Into<Vec<u8>>>(&mut self, input: I) {
+        self.input = input.into().into();
+    }
+
+    pub fn set_eof_behavior(&mut self, eof_behavior: EofBehavior) {
+        self.eof_behavior = eof_behavior;
+    }
+
+    /// Everything printed by the last run.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn output_string(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.output)
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    /// Position of the next instruction.
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// Position of the current cell.
+    pub fn dp(&self) -> usize {
+        self.dp
+    }
+
+    /// Highest position of the current cell since the last run started.
+    pub fn max_dp(&self) -> usize {
+        self.max_dp
+    }
+
+    pub fn cell(&self) -> T {
+        self.mem[self.dp]
     }
 
-    pub fn mem(&self) -> &[u8; MEM_SIZE] {
+    pub fn mem(&self) -> &[T] {
         &self.mem
     }
 
-    pub fn mem_mut(&mut self) -> &mut [u8; MEM_SIZE] {
+    /// Copies up to `len` cells starting at `start`, stopping at the end of the tape.
+    pub fn dump(&self, start: usize, len: usize) -> Vec<T> {
+        let start = start.min(self.mem.len());
+        let end = start.saturating_add(len).min(self.mem.len());
+
+        self.mem[start..end].to_vec()
+    }
+
+    /// The cells at most `radius` cells away from the current cell.
+    pub fn dump_around_dp(&self, radius: usize) -> Vec<T> {
+        let start = self.dp.saturating_sub(radius);
+        self.dump(start, (self.dp - start).saturating_add(radius).saturating_add(1))
+    }
+
+    pub fn mem_mut(&mut self) -> &mut [T] {
         &mut self.mem
     }
 
-    fn left(&mut self) {
-        self.dp += MEM_SIZE;
-        self.dp -= 1;
-        self.dp %= MEM_SIZE;
+    fn left(&mut self) -> Result<(), RunError> {
+        if self.growable {
+            self.dp = self.dp.checked_sub(1).ok_or(RunError::TapeUnderflow)?;
+        } else {
+            self.dp += self.mem.len();
+            self.dp -= 1;
+            self.dp %= self.mem.len();
+        }
+
+        self.max_dp = self.max_dp.max(self.dp);
         self.ip += 1;
+
+        Ok(())
     }
 
     fn right(&mut self) {
         self.dp += 1;
-        self.dp %= MEM_SIZE;
+
+        if self.dp == self.mem.len() {
+            if self.growable {
+                self.mem.push(T::ZERO);
+            } else {
+                self.dp = 0;
+            }
+        }
+
+        self.max_dp = self.max_dp.max(self.dp);
         self.ip += 1;
     }
 
     fn increment(&mut self) {
         let cell = self.mem[self.dp];
-        self.mem[self.dp] = cell.wrapping_add(1);
+        self.mem[self.dp] = cell.wrapping_add(T::ONE);
         self.ip += 1;
     }
 
     fn decrement(&mut self) {
         let cell = self.mem[self.dp];
-        self.mem[self.dp] = cell.wrapping_sub(1);
+        self.mem[self.dp] = cell.wrapping_sub(T::ONE);
         self.ip += 1;
     }
 
-    fn loop_start(&mut self, code: &[u8]) {
-        let cell = self.mem[self.dp];
+    fn print(&mut self) {
+        self.output.push(self.mem[self.dp].to_byte());
+        self.ip += 1;
+    }
 
-        if cell != 0 {
-            self.loop_stack.push(self.ip);
-            self.ip += 1;
-            return;
-        }
+    fn read(&mut self) {
+        let cell = &mut self.mem[self.dp];
 
-        let mut unclosed_brackets = 1;
-        while unclosed_brackets > 0 {
-            self.ip += 1;
-            match code[self.ip] {
-                b'[' => unclosed_brackets += 1,
-                b']' => unclosed_brackets -= 1,
-                _ => {},
-            }
+        *cell = match (self.input.pop_front(), self.eof_behavior) {
+            (Some(byte), _) => T::from_byte(byte),
+            (None, EofBehavior::Zero) => T::ZERO,
+            (None, EofBehavior::MinusOne) => T::MAX,
+            (None, EofBehavior::Unchanged) => *cell,
+        };
+        self.ip += 1;
+    }
+
+    fn loop_start(&mut self) {
+        if self.mem[self.dp] == T::ZERO {
+            self.ip = self.jump_table[self.ip];
         }
 
         self.ip += 1;
     }
 
     fn loop_end(&mut self) {
-        self.ip = self.loop_stack.pop().expect("unmatched ']'");
+        self.ip = self.jump_table[self.ip];
+    }
+}
+
+/// Identifies code without looking at every byte of it.
+fn code_key(code: &[u8]) -> (usize, usize) {
+    (code.as_ptr() as usize, code.len())
+}
+
+fn jump_table(code: &[u8]) -> Result<Vec<usize>, RunError> {
+    let mut jump_table = vec![0; code.len()];
+    let mut loop_starts = Vec::new();
+
+    for (ip, &op) in code.iter().enumerate() {
+        match op {
+            b'[' => loop_starts.push(ip),
+            b']' => {
+                let start = loop_starts.pop().ok_or(RunError::UnmatchedClose(ip))?;
+                jump_table[start] = ip;
+                jump_table[ip] = start;
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(start) = loop_starts.pop() {
+        return Err(RunError::UnmatchedOpen(start));
+    }
+
+    Ok(jump_table)
+}
+
+impl<T: Cell> Default for VM<T> {
+    fn default() -> Self {
+        Self::with_config(VMConfig::default())
     }
 }
 
@@ -111,10 +539,10 @@ mod tests {
     fn left() {
         let mut vm = VM::new();
 
-        vm.run("<");
+        vm.run("<").unwrap();
         assert_eq!(vm.dp, MEM_SIZE-1);
 
-        vm.run("<");
+        vm.run("<").unwrap();
         assert_eq!(vm.dp, MEM_SIZE-2);
     }
 
@@ -122,11 +550,11 @@ mod tests {
     fn right() {
         let mut vm = VM::new();
 
-        vm.run(">");
+        vm.run(">").unwrap();
         assert_eq!(vm.dp, 1);
 
         for _ in 0..MEM_SIZE {
-            vm.run(">");
+            vm.run(">").unwrap();
         }
         assert_eq!(vm.dp, 1);
     }
@@ -135,7 +563,7 @@ mod tests {
     fn increment() {
         let mut vm = VM::new();
 
-        vm.run("+>++>+++");
+        vm.run("+>++>+++").unwrap();
         assert_eq!(vm.mem()[..3], [1, 2, 3]);
     }
 
@@ -143,7 +571,7 @@ mod tests {
     fn decrement() {
         let mut vm = VM::new();
 
-        vm.run("->-->---");
+        vm.run("->-->---").unwrap();
         assert_eq!(vm.mem()[..3], [255, 254, 253]);
     }
 
@@ -151,19 +579,250 @@ mod tests {
     fn loops() {
         let mut vm = VM::new();
 
-        vm.run(">++++++[<+++++++>-]");
+        vm.run(">++++++[<+++++++>-]").unwrap();
+        assert_eq!(vm.mem()[..2], [42, 0]);
+    }
+
+    #[test]
+    fn run_reader() {
+        let mut vm = VM::new();
+        let src = io::Cursor::new("set six > ++++++ [ multiply < +++++++ > - ] done\n");
+
+        vm.run_reader(src).unwrap();
         assert_eq!(vm.mem()[..2], [42, 0]);
     }
 
+    #[test]
+    fn print() {
+        let mut vm = VM::new();
+
+        vm.run(">++++++[<+++++++>-]<.").unwrap();
+        assert_eq!(vm.output(), b"*");
+        assert_eq!(vm.output_string(), "*");
+
+        vm.run("+.").unwrap();
+        assert_eq!(vm.output(), b"+");
+    }
+
+    #[test]
+    fn read() {
+        let mut vm = VM::new();
+
+        vm.set_input([65, 66]);
+        vm.run(",>,").unwrap();
+        assert_eq!(vm.mem()[..2], [65, 66]);
+    }
+
+    #[test]
+    fn read_eof() {
+        let mut vm = VM::new();
+
+        vm.set_input("A");
+        vm.run("+>+<,>,").unwrap();
+        assert_eq!(vm.mem()[..2], [65, 0]);
+    }
+
+    #[test]
+    fn eof_behavior() {
+        let cases = [
+            (EofBehavior::Zero, 0),
+            (EofBehavior::MinusOne, 255),
+            (EofBehavior::Unchanged, 42),
+        ];
+
+        for &(eof_behavior, expected) in &cases {
+            let mut vm = VM::new();
+
+            vm.mem_mut()[1] = 42;
+            vm.set_input("A");
+            vm.set_eof_behavior(eof_behavior);
+            vm.run(",>,").unwrap();
+            assert_eq!(vm.mem()[..2], [65, expected], "{:?}", eof_behavior);
+        }
+    }
+
+    #[test]
+    fn step() {
+        let mut vm = VM::new();
+        let code = b"+++";
+
+        for expected in 1..=3 {
+            let finished = vm.step(code).unwrap();
+
+            assert_eq!(vm.mem()[0], expected);
+            assert_eq!(vm.cell(), expected);
+            assert_eq!(vm.ip(), expected as usize);
+            assert_eq!(finished, expected == 3);
+        }
+
+        assert!(vm.step(code).unwrap());
+        assert_eq!(vm.cell(), 3);
+        assert_eq!(vm.dp(), 0);
+
+        // Different code of the same length is loaded before stepping it.
+        let mut vm = VM::new();
+        vm.run("+[-]").unwrap();
+        assert!(!vm.step(b"+[>]").unwrap());
+        assert_eq!(vm.ip(), 1);
+        while !vm.step(b"+[>]").unwrap() {}
+        assert_eq!(vm.dp(), 1);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut vm = VM::new();
+        let code = b"+++[>++<-]>.";
+        vm.add_breakpoint(5);
+
+        for iteration in 1..=3 {
+            assert_eq!(vm.run_until_breakpoint(code).unwrap(), Stop::Breakpoint(5));
+            assert_eq!(vm.mem()[..2], [4 - iteration, 2 * (iteration - 1)]);
+            assert_eq!(vm.dp(), 1);
+        }
+
+        vm.clear_breakpoints();
+        assert_eq!(vm.run_until_breakpoint(code).unwrap(), Stop::Finished);
+        assert_eq!(vm.mem()[..2], [0, 6]);
+    }
+
+    #[test]
+    fn gas() {
+        let mut vm = VM::new();
+        let code = "+++[>++<-]";
+
+        assert_eq!(vm.run_with_gas(code, 10).unwrap(), (RunOutcome::OutOfGas, 0));
+        assert_eq!(vm.mem()[..2], [2, 2]);
+
+        assert_eq!(vm.resume_with_gas(code, 100).unwrap(), (RunOutcome::Completed, 85));
+        assert_eq!(vm.mem()[..2], [0, 6]);
+
+        assert_eq!(vm.run_with_gas("+ comment", 20).unwrap(), (RunOutcome::Completed, 11));
+
+        let mut vm = VM::new();
+        assert_eq!(vm.resume_with_gas("+[-]", 10).unwrap(), (RunOutcome::Completed, 5));
+        assert_eq!(vm.resume_with_gas("[", 10), Err(RunError::UnmatchedOpen(0)));
+    }
+
+    #[test]
+    fn wide_cells() {
+        let mut vm = VM::<u16>::default();
+        vm.run("++++++++++++++++[>++++++++++++++++<-]>+.").unwrap();
+        assert_eq!(vm.mem()[1], 257);
+        assert_eq!(vm.output(), [1]);
+
+        vm.run("[-]-").unwrap();
+        assert_eq!(vm.cell(), u16::MAX);
+
+        let mut vm = VM::<u32>::default();
+        vm.set_eof_behavior(EofBehavior::MinusOne);
+        vm.set_input("A");
+        vm.run(",>,").unwrap();
+        assert_eq!(vm.mem()[..2], [65, u32::MAX]);
+    }
+
+    #[test]
+    fn growable_tape() {
+        let mut vm = VM::<u8>::with_config(VMConfig { growable: true, ..VMConfig::default() });
+        vm.run(">".repeat(40_000) + "+").unwrap();
+        assert_eq!(vm.dp(), 40_000);
+        assert_eq!(vm.mem().len(), 40_001);
+        assert_eq!(vm.cell(), 1);
+
+        let mut vm = VM::<u8>::with_config(VMConfig { growable: true, ..VMConfig::default() });
+        assert_eq!(vm.run("><<"), Err(RunError::TapeUnderflow));
+    }
+
+    #[test]
+    fn run_with_input() {
+        let mut vm = VM::new();
+        vm.run(">+++").unwrap();
+
+        assert_eq!(vm.run_with_input(",.,.,.", b"abc".to_vec()).unwrap(), b"abc");
+        assert_eq!(vm.mem()[..2], [b'c', 0]);
+        assert_eq!(vm.dp(), 0);
+    }
+
+    #[test]
+    fn profile() {
+        let mut vm = VM::new();
+        vm.run("+++[>++<-]>.,").unwrap();
+
+        // `]` jumps back to its `[`, which checks the cell again.
+        assert_eq!(vm.profile(), &Profile {
+            moves: 3 * 2 + 1,
+            mutations: 3 + 3 * 3,
+            loops: 4 + 3,
+            io: 2,
+        });
+    }
+
+    #[test]
+    fn dump() {
+        let mut vm = VM::<u8>::with_config(VMConfig { mem_size: 6, ..VMConfig::default() });
+        vm.run("+>++>+++>++++<").unwrap();
+
+        assert_eq!(vm.dump(1, 3), [2, 3, 4]);
+        assert_eq!(vm.dump(4, 10), [0, 0]);
+        assert_eq!(vm.dump(10, 2), []);
+        assert_eq!(vm.dump_around_dp(1), [2, 3, 4]);
+        assert_eq!(vm.dump_around_dp(3), [1, 2, 3, 4, 0, 0]);
+        assert_eq!(vm.dump_around_dp(usize::MAX), [1, 2, 3, 4, 0, 0]);
+    }
+
+    #[test]
+    fn max_dp() {
+        let mut vm = VM::new();
+        vm.run(">>>+<<[-]>>>>+[<]").unwrap();
+        assert_eq!(vm.max_dp(), 5);
+        assert_eq!(vm.dp(), 4);
+
+        // A new run starts counting from where the last one stopped.
+        vm.run("<").unwrap();
+        assert_eq!(vm.max_dp(), 4);
+    }
+
     #[test]
     fn nested_loops() {
         let mut vm = VM::new();
-        vm.run("[[[]]]");
+        vm.run("[[[]]]").unwrap();
+    }
+
+    #[test]
+    fn skipped_loops() {
+        let mut vm = VM::new();
+        let code = "[[-]>[+[-]<]]".repeat(10_000) + "+++";
+
+        vm.run(code).unwrap();
+        assert_eq!(vm.mem()[..2], [3, 0]);
     }
 
     #[test]
-    #[should_panic(expected = "unmatched ']'")]
     fn unbalanced_loops() {
-        VM::new().run("]");
+        assert_eq!(VM::new().run("+["), Err(RunError::UnmatchedOpen(1)));
+        assert_eq!(VM::new().run("[]]"), Err(RunError::UnmatchedClose(2)));
+    }
+
+    #[test]
+    fn step_limit() {
+        assert_eq!(VM::new().run("+[]"), Err(RunError::StepLimitExceeded));
+
+        let mut vm = VM::<u8>::with_config(VMConfig { max_steps: 5, ..VMConfig::default() });
+        assert_eq!(vm.run("+++++"), Ok(()));
+        assert_eq!(vm.run("++++++"), Err(RunError::StepLimitExceeded));
+    }
+
+    #[test]
+    #[should_panic(expected = "the tape needs at least one cell")]
+    fn empty_tape() {
+        VM::<u8>::with_config(VMConfig { mem_size: 0, ..VMConfig::default() });
+    }
+
+    #[test]
+    fn small_tape() {
+        let mut vm = VM::<u8>::with_config(VMConfig { mem_size: 4, ..VMConfig::default() });
+
+        vm.run("<+>>++").unwrap();
+        assert_eq!(vm.mem(), [0, 2, 0, 1]);
+        assert_eq!(vm.dp, 1);
     }
 }
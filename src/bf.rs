@@ -0,0 +1,107 @@
+use std::error::Error;
+use std::fmt;
+
+/// A brainfuck program as a tree, with runs of `+`/`-` and `<`/`>` merged.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BfOp {
+    Move(isize),
+    Add(u8),
+    Loop(Vec<BfOp>),
+    Print,
+    Read,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// The `[` at this byte offset is never closed.
+    UnmatchedOpen(usize),
+    /// The `]` at this byte offset has no `[` to close.
+    UnmatchedClose(usize),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnmatchedOpen(position) => write!(f, "Unmatched '[' at offset {}", position),
+            ParseError::UnmatchedClose(position) => write!(f, "Unmatched ']' at offset {}", position),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// Parses brainfuck code, ignoring everything that isn't a command.
+pub fn parse_bf(code: &str) -> Result<Vec<BfOp>, ParseError> {
+    let mut blocks = vec![Vec::new()];
+    let mut loop_starts = Vec::new();
+
+    for (position, op) in code.char_indices() {
+        let block = blocks.last_mut().unwrap();
+
+        match op {
+            '+' => push(block, BfOp::Add(1)),
+            '-' => push(block, BfOp::Add(255)),
+            '>' => push(block, BfOp::Move(1)),
+            '<' => push(block, BfOp::Move(-1)),
+            '.' => block.push(BfOp::Print),
+            ',' => block.push(BfOp::Read),
+            '[' => {
+                loop_starts.push(position);
+                blocks.push(Vec::new());
+            }
+            ']' => {
+                loop_starts.pop().ok_or(ParseError::UnmatchedClose(position))?;
+                let body = blocks.pop().unwrap();
+                blocks.last_mut().unwrap().push(BfOp::Loop(body));
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(position) = loop_starts.pop() {
+        return Err(ParseError::UnmatchedOpen(position));
+    }
+
+    Ok(blocks.pop().unwrap())
+}
+
+/// Pushes `op`, merging it into the previous op of the same kind.
+fn push(block: &mut Vec<BfOp>, op: BfOp) {
+    let merged = match (block.last(), &op) {
+        (Some(BfOp::Add(a)), BfOp::Add(b)) => BfOp::Add(a.wrapping_add(*b)),
+        (Some(BfOp::Move(a)), BfOp::Move(b)) => BfOp::Move(a + b),
+        _ => return block.push(op),
+    };
+
+    block.pop();
+
+    if merged != BfOp::Add(0) && merged != BfOp::Move(0) {
+        block.push(merged);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use BfOp::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_bf("+++[->+<]").unwrap(), [
+            Add(3),
+            Loop(vec![Add(255), Move(1), Add(1), Move(-1)]),
+        ]);
+        assert_eq!(parse_bf("a+-<<>.,[[]]").unwrap(), [
+            Move(-1),
+            Print,
+            Read,
+            Loop(vec![Loop(vec![])]),
+        ]);
+    }
+
+    #[test]
+    fn unmatched() {
+        assert_eq!(parse_bf("+[[]"), Err(ParseError::UnmatchedOpen(1)));
+        assert_eq!(parse_bf("+]["), Err(ParseError::UnmatchedClose(1)));
+    }
+}
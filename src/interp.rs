@@ -0,0 +1,375 @@
+//! Runs IR programs directly, without translating them to brainfuck first.
+
+use crate::ir::*;
+use crate::trans::undeclared_assign_target;
+use std::collections::{HashMap, VecDeque};
+
+pub type Result<T = (), E = Error> = std::result::Result<T, E>;
+pub type Error = Box<dyn std::error::Error>;
+
+/// Runs `ir` without input and returns the final value of every variable.
+pub fn eval(ir: &IR) -> Result<HashMap<String, u8>> {
+    Ok(eval_with_input(ir, &[])?.vars)
+}
+
+pub fn eval_with_input(ir: &IR, input: &[u8]) -> Result<Evaluation> {
+    if ir.config.cell_width != CellWidth::U8 {
+        Err(format!("Cell width {:?} is not supported, only u8 cells are", ir.config.cell_width))?;
+    }
+
+    let mut interp = Interp {
+        scopes: Vec::new(),
+        decls: 0,
+        finished: HashMap::new(),
+        input: input.iter().copied().collect(),
+        output: Vec::new(),
+        loop_depth: 0,
+    };
+
+    interp.push_scope();
+    interp.exec_block(&ir.stmts)?;
+    interp.pop_scope();
+
+    Ok(Evaluation {
+        vars: interp.finished.into_iter()
+            .map(|(name, (_, value))| (name, value))
+            .collect(),
+        output: interp.output,
+    })
+}
+
+#[derive(Debug)]
+pub struct Evaluation {
+    /// The final value of every declared variable.
+    /// If a name is declared more than once, the declaration made last wins,
+    /// like in `Translation::symbols`.
+    pub vars: HashMap<String, u8>,
+    pub output: Vec<u8>,
+}
+
+/// How a statement left the block it is part of.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Flow {
+    Next,
+    Break,
+    Continue,
+}
+
+struct Interp {
+    /// Variables in scope with the number of declarations made before them, innermost scope last.
+    scopes: Vec<Vec<(Ident, usize, u8)>>,
+    decls: usize,
+    /// Variables whose scope has ended.
+    finished: HashMap<String, (usize, u8)>,
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+    loop_depth: usize,
+}
+
+impl Interp {
+    fn exec_block(&mut self, stmts: &[Statement]) -> Result<Flow> {
+        for stmt in stmts {
+            let flow = self.exec_stmt(stmt)?;
+
+            if flow != Flow::Next {
+                return Ok(flow);
+            }
+        }
+
+        Ok(Flow::Next)
+    }
+
+    fn exec_stmt(&mut self, stmt: &Statement) -> Result<Flow> {
+        match stmt {
+            Statement::Decl(Decl { name, value }) => {
+                let value = match value {
+                    Some(value) => self.eval_expr(value)?,
+                    None => 0,
+                };
+                self.scopes.last_mut().unwrap().push((name.clone(), self.decls, value));
+                self.decls += 1;
+            }
+            Statement::Assign(Assign { name, value }) => {
+                let value = self.eval_expr(value)?;
+                *self.resolve_assign_target(name)? = value;
+            }
+            Statement::AddAssign(AddAssign { name, value }) => {
+                let value = self.eval_expr(value)?;
+                let var = self.resolve_assign_target(name)?;
+                *var = var.wrapping_add(value);
+            }
+            Statement::SubAssign(SubAssign { name, value }) => {
+                let value = self.eval_expr(value)?;
+                let var = self.resolve_assign_target(name)?;
+                *var = var.wrapping_sub(value);
+            }
+            Statement::MulAssign(MulAssign { name, value }) => {
+                let value = self.eval_expr(value)?;
+                let var = self.resolve_assign_target(name)?;
+                *var = var.wrapping_mul(value);
+            }
+            Statement::DivAssign(DivAssign { name, value }) => {
+                let value = self.eval_expr(value)?;
+                let var = self.resolve_assign_target(name)?;
+                *var = var.checked_div(value).unwrap_or(0);
+            }
+            Statement::While(While { cond, body }) => {
+                self.loop_depth += 1;
+
+                while self.eval_expr(cond)? != 0 {
+                    if self.exec_scoped(body)? == Flow::Break {
+                        break;
+                    }
+                }
+
+                self.loop_depth -= 1;
+            }
+            Statement::If(If { cond, body, else_body }) => {
+                let body = if self.eval_expr(cond)? != 0 { body } else { else_body };
+                return self.exec_scoped(body);
+            }
+            Statement::Read(name) => {
+                let byte = self.read();
+                *self.resolve_assign_target(name)? = byte;
+            }
+            Statement::Break | Statement::Continue if self.loop_depth == 0 => {
+                let keyword = if *stmt == Statement::Break { "break" } else { "continue" };
+                Err(format!("'{}' is only allowed inside of a loop", keyword))?;
+            }
+            Statement::Break => return Ok(Flow::Break),
+            Statement::Continue => return Ok(Flow::Continue),
+            Statement::Call(call) => {
+                self.call(call)?;
+            }
+        }
+
+        Ok(Flow::Next)
+    }
+
+    fn exec_scoped(&mut self, stmts: &[Statement]) -> Result<Flow> {
+        self.push_scope();
+        let flow = self.exec_block(stmts);
+        self.pop_scope();
+
+        flow
+    }
+
+    fn eval_expr(&mut self, expr: &Expr) -> Result<u8> {
+        Ok(match expr {
+            Expr::Const(value) => *value,
+            Expr::Var(name) => *self.resolve_var(name)?,
+            Expr::Add(a, b) => self.eval_expr(a)?.wrapping_add(self.eval_expr(b)?),
+            Expr::Sub(a, b) => self.eval_expr(a)?.wrapping_sub(self.eval_expr(b)?),
+            Expr::Mul(a, b) => self.eval_expr(a)?.wrapping_mul(self.eval_expr(b)?),
+            Expr::Gt(a, b) => (self.eval_expr(a)? > self.eval_expr(b)?) as u8,
+            Expr::Lt(a, b) => (self.eval_expr(a)? < self.eval_expr(b)?) as u8,
+            Expr::Eq(a, b) => (self.eval_expr(a)? == self.eval_expr(b)?) as u8,
+            Expr::Ne(a, b) => (self.eval_expr(a)? != self.eval_expr(b)?) as u8,
+            // Both operands are evaluated, like in the translated code.
+            Expr::And(a, b) => {
+                let (a, b) = (self.eval_expr(a)?, self.eval_expr(b)?);
+                (a != 0 && b != 0) as u8
+            }
+            Expr::Or(a, b) => {
+                let (a, b) = (self.eval_expr(a)?, self.eval_expr(b)?);
+                (a != 0 || b != 0) as u8
+            }
+            Expr::Not(a) => (self.eval_expr(a)? == 0) as u8,
+            Expr::Call(call) => self.call(call)?,
+        })
+    }
+
+    fn call(&mut self, Call { name, args }: &Call) -> Result<u8> {
+        let arity = match &**name {
+            "read_num" => 0,
+            "print_num" => 1,
+            "min" | "max" | "abs_diff" => 2,
+            _ => Err(format!("Unknown function '{}'", &**name))?,
+        };
+
+        if args.len() != arity {
+            Err(format!("Function '{}' takes {} arguments, but {} were given", &**name, arity, args.len()))?;
+        }
+
+        let args = args.iter()
+            .map(|arg| self.eval_expr(arg))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(match &**name {
+            "read_num" => self.read_num(),
+            "print_num" => {
+                self.output.extend(args[0].to_string().bytes());
+                0
+            }
+            "min" => args[0].min(args[1]),
+            "max" => args[0].max(args[1]),
+            "abs_diff" => args[0].max(args[1]) - args[0].min(args[1]),
+            name => Err(format!("BUG: unhandled builtin: {}", name))?,
+        })
+    }
+
+    /// Reads one byte, or 0 at the end of input.
+    fn read(&mut self) -> u8 {
+        self.input.pop_front().unwrap_or(0)
+    }
+
+    /// Like `Context::read_u8_decimal`, consumes the first byte that is not a digit.
+    fn read_num(&mut self) -> u8 {
+        let mut value = 0u8;
+        let mut byte = self.read();
+
+        while byte.is_ascii_digit() {
+            value = value.wrapping_mul(10).wrapping_add(byte - b'0');
+            byte = self.read();
+        }
+
+        value
+    }
+
+    fn push_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    fn pop_scope(&mut self) {
+        for (name, decl, value) in self.scopes.pop().unwrap() {
+            let entry = self.finished.entry(name.to_string()).or_insert((decl, value));
+
+            if entry.0 <= decl {
+                *entry = (decl, value);
+            }
+        }
+    }
+
+    fn resolve_var(&mut self, name: &Ident) -> Result<&mut u8> {
+        self.scopes.iter_mut()
+            .rev()
+            .flat_map(|scope| scope.iter_mut().rev())
+            .find(|(var, _, _)| var == name)
+            .map(|(_, _, value)| value)
+            .ok_or_else(|| format!("Variable '{}' is not in scope", &**name).into())
+    }
+
+    fn resolve_assign_target(&mut self, name: &Ident) -> Result<&mut u8> {
+        self.resolve_var(name)
+            .map_err(|_| undeclared_assign_target(name).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trans::trans_with_symbols;
+    use minibf::VM;
+
+    /// Checks that the interpreter and the translated code agree on every variable.
+    fn assert_agrees(src: &str, input: &str) -> Evaluation {
+        let ir = IR::parse_str(src).unwrap();
+        let evaluation = eval_with_input(&ir, input.as_bytes()).unwrap();
+        let (code, symbols) = trans_with_symbols(&ir).unwrap();
+
+        let mut vm = VM::new();
+        vm.set_input(input);
+        vm.run(code).unwrap();
+
+        assert_eq!(evaluation.vars.len(), symbols.len());
+        for (name, addr) in symbols {
+            assert_eq!(evaluation.vars[&name], vm.mem()[addr as usize], "value of '{}'", name);
+        }
+        assert_eq!(evaluation.output, vm.output());
+
+        evaluation
+    }
+
+    #[test]
+    fn arithmetic() {
+        let evaluation = assert_agrees("
+            let a = 200
+            let b = a + 100
+            let c = 3 - 5
+            let d = b * 7
+            let e = d
+            e /= 4
+            let f = (a > b) + (b < a) * 2 + (c == 254) * 4 + (a != a) * 8
+            let g = !a || !(b && c)
+        ", "");
+
+        assert_eq!(evaluation.vars["b"], 44);
+        assert_eq!(evaluation.vars["c"], 254);
+        assert_eq!(evaluation.vars["f"], 7);
+    }
+
+    #[test]
+    fn control_flow() {
+        assert_agrees("
+            let i = 0
+            let evens = 0
+            let odds = 0
+            let odd = 0
+            while i < 10 {
+                i += 1
+                if i == 9 {
+                    break
+                }
+                odd = i
+                odd /= 2
+                odd = i - odd * 2
+                if odd {
+                    odds += i
+                    continue
+                } else {
+                    evens += i
+                }
+                evens += 100
+            }
+        ", "");
+    }
+
+    #[test]
+    fn truthiness_and_shadowing() {
+        let evaluation = assert_agrees("
+            let c = 6
+            if 29 {
+                c += 1
+            }
+            let x = 2
+            if x * 3 {
+                c += 10
+            } else {
+                c += 100
+            }
+            let d = 5
+            let d = d + 1
+        ", "");
+
+        assert_eq!(evaluation.vars["c"], 17);
+        assert_eq!(evaluation.vars["d"], 6);
+    }
+
+    #[test]
+    fn io() {
+        let evaluation = assert_agrees("
+            let first
+            read first
+            let n = read_num()
+            let doubled = n * 2
+            print_num(max(doubled, abs_diff(n, 100)))
+            let eof
+            read eof
+        ", "A42\n");
+
+        assert_eq!(evaluation.vars["first"], b'A');
+        assert_eq!(evaluation.output, b"84");
+    }
+
+    #[test]
+    fn errors() {
+        let err = eval(&IR::parse_str("x = 1").unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "Cannot assign to undeclared variable 'x', declare it first with 'let x'");
+
+        let err = eval(&IR::parse_str("let y y = x").unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "Variable 'x' is not in scope");
+
+        let err = eval(&IR::parse_str("continue").unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "'continue' is only allowed inside of a loop");
+    }
+}
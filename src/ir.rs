@@ -1,6 +1,9 @@
 use pest::Parser as _;
+use pest::error::LineColLocation;
 use pest::prec_climber::{PrecClimber, Assoc, Operator};
 use pest_derive::*;
+use std::fmt;
+use std::num::ParseIntError;
 use std::ops::Deref;
 
 #[derive(Parser)]
@@ -8,35 +11,178 @@ use std::ops::Deref;
 struct Parser;
 
 pub type Result<T = (), E = Error> = std::result::Result<T, E>;
-pub type Error = Box<std::error::Error>;
+pub type Error = IrError;
 pub type Pair<'a, R = Rule> = pest::iterators::Pair<'a, R>;
 pub type Pairs<'a, R = Rule> = pest::iterators::Pairs<'a, R>;
 
+#[derive(Debug)]
+pub enum IrError {
+    /// The input does not match the grammar.
+    Parse(Box<pest::error::Error<Rule>>),
+    /// A number in a directive is malformed or too large.
+    Int(ParseIntError),
+    LiteralOutOfRange {
+        literal: String,
+        line: usize,
+        col: usize,
+    },
+    /// The parser produced a pair the AST builder did not expect. Always a bug.
+    UnexpectedRule {
+        expected: Rule,
+        found: Rule,
+    },
+    /// The AST builder has no case for a rule the grammar allows. Always a bug.
+    UnhandledRule(Rule),
+}
+
+impl fmt::Display for IrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IrError::Parse(err) => {
+                let (line, col) = self.line_col().unwrap_or_default();
+                write!(f, "syntax error at line {}, column {}\n{}", line, col, err)
+            }
+            IrError::Int(err) => write!(f, "{}", err),
+            IrError::LiteralOutOfRange { literal, line, col } => {
+                write!(f, "literal {} out of range for u8 cell (max 255) at line {}, column {}", literal, line, col)
+            }
+            IrError::UnexpectedRule { expected, found } => write!(f, "BUG: Expected {:?}, found {:?}", expected, found),
+            IrError::UnhandledRule(rule) => write!(f, "BUG: Unhandled rule: {:?}", rule),
+        }
+    }
+}
+
+impl IrError {
+    /// Where in the source the error occurred, if known.
+    pub fn line_col(&self) -> Option<(usize, usize)> {
+        match self {
+            IrError::Parse(err) => Some(match err.line_col {
+                LineColLocation::Pos(pos) | LineColLocation::Span(pos, _) => pos,
+            }),
+            IrError::LiteralOutOfRange { line, col, .. } => Some((*line, *col)),
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for IrError {}
+
+impl From<pest::error::Error<Rule>> for IrError {
+    fn from(err: pest::error::Error<Rule>) -> Self {
+        IrError::Parse(Box::new(err))
+    }
+}
+
+impl From<ParseIntError> for IrError {
+    fn from(err: ParseIntError) -> Self {
+        IrError::Int(err)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct IR {
+    pub config: Config,
     pub stmts: Vec<Statement>,
 }
 
 impl IR {
     pub fn parse_str(code: &str) -> Result<Self> {
-        let pairs = Parser::parse(Rule::ir, code)?.into_iter();
+        let pairs = Parser::parse(Rule::ir, code)?;
+        let mut config = Config::default();
+        let mut stmts = Vec::new();
+
+        for pair in pairs {
+            match pair.as_rule() {
+                Rule::directive => config.apply(pair)?,
+                Rule::stmt => stmts.push(Statement::parse(pair)?),
+                _ => {}
+            }
+        }
 
         Ok(Self {
-            stmts: pairs
-                .filter(|pair| pair.as_rule() != Rule::EOI)
-                .map(Statement::parse)
-                .collect::<Result<_>>()?,
+            config,
+            stmts,
         })
     }
 }
 
+/// Runtime requirements declared by `#` directives at the top of a program.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Config {
+    /// Number of cells the target tape provides (`#tape 10000`).
+    pub tape_size: usize,
+    /// Width of a single cell (`#cells u16`).
+    pub cell_width: CellWidth,
+}
+
+impl Config {
+    fn apply(&mut self, pair: Pair) -> Result {
+        ensure_rule(&pair, Rule::directive)?;
+
+        let pair = pair.into_inner().next().unwrap();
+        let value = pair.into_inner().next().unwrap();
+
+        match value.as_rule() {
+            Rule::number => self.tape_size = value.as_str().parse()?,
+            Rule::cell_width => self.cell_width = CellWidth::parse(value)?,
+            rule => Err(IrError::UnhandledRule(rule))?,
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tape_size: 30_000,
+            cell_width: CellWidth::U8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CellWidth {
+    U8,
+    U16,
+    U32,
+}
+
+impl CellWidth {
+    fn parse(pair: Pair) -> Result<Self> {
+        ensure_rule(&pair, Rule::cell_width)?;
+
+        Ok(match pair.as_str() {
+            "u8" => CellWidth::U8,
+            "u16" => CellWidth::U16,
+            "u32" => CellWidth::U32,
+            _ => Err(IrError::UnhandledRule(pair.as_rule()))?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Statement {
     Decl(Decl),
     Assign(Assign),
     AddAssign(AddAssign),
+    SubAssign(SubAssign),
+    MulAssign(MulAssign),
+    /// Division by zero sets the variable to 0.
+    DivAssign(DivAssign),
     While(While),
     If(If),
+    /// Reads one byte of input into the variable.
+    Read(Ident),
+    /// Leaves the innermost loop.
+    Break,
+    /// Skips the rest of the innermost loop's body.
+    Continue,
+    Call(Call),
 }
 
 impl Statement {
@@ -49,14 +195,42 @@ impl Statement {
             Rule::stmt_decl => Statement::Decl(Decl::parse(pair)?),
             Rule::stmt_assign => Statement::Assign(Assign::parse(pair)?),
             Rule::stmt_add_assign => Statement::AddAssign(AddAssign::parse(pair)?),
+            Rule::stmt_sub_assign => Statement::SubAssign(SubAssign::parse(pair)?),
+            Rule::stmt_mul_assign => Statement::MulAssign(MulAssign::parse(pair)?),
+            Rule::stmt_div_assign => Statement::DivAssign(DivAssign::parse(pair)?),
             Rule::stmt_while => Statement::While(While::parse(pair)?),
             Rule::stmt_if => Statement::If(If::parse(pair)?),
-            rule => Err(format!("BUG: unhandled stmt rule: {:?}", rule))?,
+            Rule::stmt_read => Statement::Read(Ident::parse(pair.into_inner().next().unwrap())?),
+            Rule::stmt_break => Statement::Break,
+            Rule::stmt_continue => Statement::Continue,
+            Rule::call => Statement::Call(Call::parse(pair)?),
+            rule => Err(IrError::UnhandledRule(rule))?,
         })
     }
+
+    /// Whether the statement, including any nested body, reads the variable `name`.
+    pub fn reads(&self, name: &Ident) -> bool {
+        match self {
+            Statement::Decl(Decl { value, .. }) => value.as_ref().is_some_and(|value| value.reads(name)),
+            Statement::Assign(Assign { value, .. }) |
+            Statement::AddAssign(AddAssign { value, .. }) |
+            Statement::SubAssign(SubAssign { value, .. }) |
+            Statement::MulAssign(MulAssign { value, .. }) |
+            Statement::DivAssign(DivAssign { value, .. }) => value.reads(name),
+            Statement::While(While { cond, body }) => {
+                cond.reads(name) || body.iter().any(|stmt| stmt.reads(name))
+            }
+            Statement::If(If { cond, body, else_body }) => {
+                cond.reads(name) || body.iter().chain(else_body).any(|stmt| stmt.reads(name))
+            }
+            Statement::Read(_) | Statement::Break | Statement::Continue => false,
+            Statement::Call(call) => call.reads(name),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Decl {
     pub name: Ident,
     pub value: Option<Expr>,
@@ -76,6 +250,7 @@ impl Decl {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Assign {
     pub name: Ident,
     pub value: Expr,
@@ -95,6 +270,7 @@ impl Assign {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AddAssign {
     pub name: Ident,
     pub value: Expr,
@@ -113,8 +289,69 @@ impl AddAssign {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SubAssign {
+    pub name: Ident,
+    pub value: Expr,
+}
+
+impl SubAssign {
+    fn parse(pair: Pair) -> Result<Self> {
+        ensure_rule(&pair, Rule::stmt_sub_assign)?;
+
+        let mut pairs = pair.into_inner();
+
+        Ok(Self {
+            name: Ident::parse(pairs.next().unwrap())?,
+            value: Expr::parse(pairs.next().unwrap())?,
+        })
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MulAssign {
+    pub name: Ident,
+    pub value: Expr,
+}
+
+impl MulAssign {
+    fn parse(pair: Pair) -> Result<Self> {
+        ensure_rule(&pair, Rule::stmt_mul_assign)?;
+
+        let mut pairs = pair.into_inner();
+
+        Ok(Self {
+            name: Ident::parse(pairs.next().unwrap())?,
+            value: Expr::parse(pairs.next().unwrap())?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DivAssign {
+    pub name: Ident,
+    pub value: Expr,
+}
+
+impl DivAssign {
+    fn parse(pair: Pair) -> Result<Self> {
+        ensure_rule(&pair, Rule::stmt_div_assign)?;
+
+        let mut pairs = pair.into_inner();
+
+        Ok(Self {
+            name: Ident::parse(pairs.next().unwrap())?,
+            value: Expr::parse(pairs.next().unwrap())?,
+        })
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct While {
     pub cond: Expr,
     pub body: Vec<Statement>,
@@ -134,9 +371,12 @@ impl While {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct If {
     pub cond: Expr,
     pub body: Vec<Statement>,
+    /// Empty if the `if` has no `else` block.
+    pub else_body: Vec<Statement>,
 }
 
 impl If {
@@ -144,33 +384,82 @@ impl If {
         ensure_rule(&pair, Rule::stmt_if)?;
 
         let mut pairs = pair.into_inner();
+        let cond = Expr::parse(pairs.next().unwrap())?;
+        let mut body = Vec::new();
+        let mut else_body = Vec::new();
+
+        for pair in pairs {
+            match pair.as_rule() {
+                Rule::else_block => else_body = pair.into_inner().map(Statement::parse).collect::<Result<_>>()?,
+                _ => body.push(Statement::parse(pair)?),
+            }
+        }
 
         Ok(Self {
-            cond: Expr::parse(pairs.next().unwrap())?,
-            body: pairs.map(Statement::parse).collect::<Result<_>>()?,
+            cond,
+            body,
+            else_body,
         })
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Call {
+    pub name: Ident,
+    pub args: Vec<Expr>,
+}
+
+impl Call {
+    fn parse(pair: Pair) -> Result<Self> {
+        ensure_rule(&pair, Rule::call)?;
+
+        let mut pairs = pair.into_inner();
+
+        Ok(Self {
+            name: Ident::parse(pairs.next().unwrap())?,
+            args: pairs.map(Expr::parse).collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn reads(&self, name: &Ident) -> bool {
+        self.args.iter().any(|arg| arg.reads(name))
+    }
+}
+
 lazy_static! {
     static ref EXPR_CLIMBER: PrecClimber<Rule> = {
         use Rule::*;
         use Assoc::*;
 
         PrecClimber::new(vec![
-            Operator::new(op_gt, Left),
+            Operator::new(op_or, Left),
+            Operator::new(op_and, Left),
+            Operator::new(op_eq, Left) | Operator::new(op_ne, Left),
+            Operator::new(op_gt, Left) | Operator::new(op_lt, Left),
             Operator::new(op_add, Left) | Operator::new(op_sub, Left),
+            Operator::new(op_mul, Left),
         ])
     };
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Expr {
     Const(u8),
     Var(Ident),
     Add(Box<Expr>, Box<Expr>),
     Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
     Gt(Box<Expr>, Box<Expr>),
+    Lt(Box<Expr>, Box<Expr>),
+    Eq(Box<Expr>, Box<Expr>),
+    Ne(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    /// 1 if the operand is zero, 0 otherwise.
+    Not(Box<Expr>),
+    Call(Call),
 }
 
 impl Expr {
@@ -186,13 +475,18 @@ impl Expr {
 
     fn parse_term(pair: Pair) -> Result<Self> {
         let rule = pair.as_rule();
-        let mut pairs = pair.into_inner();
+        let mut pairs = pair.clone().into_inner();
 
         Ok(match rule {
-            Rule::expr_const => Expr::Const(pairs.as_str().parse()?),
+            Rule::expr_const => Expr::Const(parse_literal(pair)?),
+            Rule::expr_bool => Expr::Const((pair.as_str() == "true") as u8),
             Rule::expr_char => Expr::Const(pairs.as_str().as_bytes()[0]),
             Rule::expr_var => Expr::Var(Ident::parse(pairs.next().unwrap())?),
-            rule => Err(format!("BUG: Unhandled term rule: {:?}", rule))?,
+            Rule::call => Expr::Call(Call::parse(pair)?),
+            Rule::expr_not => Expr::Not(Box::new(Expr::parse_term(pairs.next().unwrap())?)),
+            // A parenthesized group; climbing starts over inside it.
+            Rule::expr => Expr::parse(pair)?,
+            rule => Err(IrError::UnhandledRule(rule))?,
         })
     }
 
@@ -203,8 +497,14 @@ impl Expr {
         Ok(match op.as_rule() {
             Rule::op_add => Expr::Add(lhs, rhs),
             Rule::op_sub => Expr::Sub(lhs, rhs),
+            Rule::op_mul => Expr::Mul(lhs, rhs),
             Rule::op_gt => Expr::Gt(lhs, rhs),
-            rule => Err(format!("BUG: Unhandled op rule: {:?}", rule))?,
+            Rule::op_lt => Expr::Lt(lhs, rhs),
+            Rule::op_eq => Expr::Eq(lhs, rhs),
+            Rule::op_ne => Expr::Ne(lhs, rhs),
+            Rule::op_and => Expr::And(lhs, rhs),
+            Rule::op_or => Expr::Or(lhs, rhs),
+            rule => Err(IrError::UnhandledRule(rule))?,
         })
     }
 
@@ -214,12 +514,59 @@ impl Expr {
             Expr::Var(_) => return None,
             Expr::Add(a, b) => a.const_value()?.wrapping_add(b.const_value()?),
             Expr::Sub(a, b) => a.const_value()?.wrapping_sub(b.const_value()?),
+            Expr::Mul(a, b) => a.const_value()?.wrapping_mul(b.const_value()?),
             Expr::Gt(a, b) => (a.const_value()? > b.const_value()?) as u8,
+            Expr::Lt(a, b) => (a.const_value()? < b.const_value()?) as u8,
+            Expr::Eq(a, b) => (a.const_value()? == b.const_value()?) as u8,
+            Expr::Ne(a, b) => (a.const_value()? != b.const_value()?) as u8,
+            Expr::And(a, b) => (a.const_value()? != 0 && b.const_value()? != 0) as u8,
+            Expr::Or(a, b) => (a.const_value()? != 0 || b.const_value()? != 0) as u8,
+            Expr::Not(a) => (a.const_value()? == 0) as u8,
+            Expr::Call(_) => return None,
         })
     }
+
+    pub fn reads(&self, name: &Ident) -> bool {
+        match self {
+            Expr::Const(_) => false,
+            Expr::Var(var) => var == name,
+            Expr::Add(a, b) |
+            Expr::Sub(a, b) |
+            Expr::Mul(a, b) |
+            Expr::Gt(a, b) |
+            Expr::Lt(a, b) |
+            Expr::Eq(a, b) |
+            Expr::Ne(a, b) |
+            Expr::And(a, b) |
+            Expr::Or(a, b) => a.reads(name) || b.reads(name),
+            Expr::Not(a) => a.reads(name),
+            Expr::Call(call) => call.reads(name),
+        }
+    }
+
+    /// Whether evaluating the expression does anything besides producing its value.
+    pub fn has_side_effects(&self) -> bool {
+        match self {
+            Expr::Const(_) | Expr::Var(_) => false,
+            Expr::Add(a, b) |
+            Expr::Sub(a, b) |
+            Expr::Mul(a, b) |
+            Expr::Gt(a, b) |
+            Expr::Lt(a, b) |
+            Expr::Eq(a, b) |
+            Expr::Ne(a, b) |
+            Expr::And(a, b) |
+            Expr::Or(a, b) => a.has_side_effects() || b.has_side_effects(),
+            Expr::Not(a) => a.has_side_effects(),
+            // Functions may do I/O.
+            Expr::Call(_) => true,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct Ident(String);
 
 impl Ident {
@@ -238,9 +585,177 @@ impl Deref for Ident {
     }
 }
 
+impl fmt::Display for IR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let default = Config::default();
+
+        if self.config.tape_size != default.tape_size {
+            writeln!(f, "#tape {}", self.config.tape_size)?;
+        }
+
+        if self.config.cell_width != default.cell_width {
+            let width = match self.config.cell_width {
+                CellWidth::U8 => "u8",
+                CellWidth::U16 => "u16",
+                CellWidth::U32 => "u32",
+            };
+            writeln!(f, "#cells {}", width)?;
+        }
+
+        for stmt in &self.stmts {
+            writeln!(f, "{}", stmt)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Statement {
+    fn fmt_indented(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        write!(f, "{:1$}", "", indent * 4)?;
+
+        match self {
+            Statement::Decl(Decl { name, value: None }) => write!(f, "let {}", name),
+            Statement::Decl(Decl { name, value: Some(value) }) => write!(f, "let {} = {}", name, value),
+            Statement::Assign(Assign { name, value }) => write!(f, "{} = {}", name, value),
+            Statement::AddAssign(AddAssign { name, value }) => write!(f, "{} += {}", name, value),
+            Statement::SubAssign(SubAssign { name, value }) => write!(f, "{} -= {}", name, value),
+            Statement::MulAssign(MulAssign { name, value }) => write!(f, "{} *= {}", name, value),
+            Statement::DivAssign(DivAssign { name, value }) => write!(f, "{} /= {}", name, value),
+            Statement::While(While { cond, body }) => {
+                write!(f, "while {} ", cond)?;
+                fmt_block(f, body, indent)
+            }
+            Statement::If(If { cond, body, else_body }) => {
+                write!(f, "if {} ", cond)?;
+                fmt_block(f, body, indent)?;
+
+                if !else_body.is_empty() {
+                    write!(f, " else ")?;
+                    fmt_block(f, else_body, indent)?;
+                }
+
+                Ok(())
+            }
+            Statement::Read(name) => write!(f, "read {}", name),
+            Statement::Break => write!(f, "break"),
+            Statement::Continue => write!(f, "continue"),
+            Statement::Call(call) => write!(f, "{}", call),
+        }
+    }
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+fn fmt_block(f: &mut fmt::Formatter, stmts: &[Statement], indent: usize) -> fmt::Result {
+    writeln!(f, "{{")?;
+
+    for stmt in stmts {
+        stmt.fmt_indented(f, indent + 1)?;
+        writeln!(f)?;
+    }
+
+    write!(f, "{:1$}}}", "", indent * 4)
+}
+
+impl Expr {
+    /// How tightly the expression binds, used to decide where parentheses are needed.
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Or(..) => 1,
+            Expr::And(..) => 2,
+            Expr::Eq(..) | Expr::Ne(..) => 3,
+            Expr::Gt(..) | Expr::Lt(..) => 4,
+            Expr::Add(..) | Expr::Sub(..) => 5,
+            Expr::Mul(..) => 6,
+            Expr::Const(_) | Expr::Var(_) | Expr::Not(_) | Expr::Call(_) => 7,
+        }
+    }
+
+    fn fmt_operand(&self, f: &mut fmt::Formatter, parens: bool) -> fmt::Result {
+        if parens {
+            write!(f, "({})", self)
+        } else {
+            write!(f, "{}", self)
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (a, op, b) = match self {
+            Expr::Const(value) => return write!(f, "{}", value),
+            Expr::Var(name) => return write!(f, "{}", name),
+            Expr::Call(call) => return write!(f, "{}", call),
+            Expr::Not(a) => {
+                write!(f, "!")?;
+                return a.fmt_operand(f, a.precedence() < self.precedence());
+            }
+            Expr::Add(a, b) => (a, "+", b),
+            Expr::Sub(a, b) => (a, "-", b),
+            Expr::Mul(a, b) => (a, "*", b),
+            Expr::Gt(a, b) => (a, ">", b),
+            Expr::Lt(a, b) => (a, "<", b),
+            Expr::Eq(a, b) => (a, "==", b),
+            Expr::Ne(a, b) => (a, "!=", b),
+            Expr::And(a, b) => (a, "&&", b),
+            Expr::Or(a, b) => (a, "||", b),
+        };
+
+        // All binary operators are left associative.
+        a.fmt_operand(f, a.precedence() < self.precedence())?;
+        write!(f, " {} ", op)?;
+        b.fmt_operand(f, b.precedence() <= self.precedence())
+    }
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}(", self.name)?;
+
+        for (i, arg) in self.args.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+
+            write!(f, "{}", arg)?;
+        }
+
+        write!(f, ")")
+    }
+}
+
+impl fmt::Display for Ident {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn parse_literal(pair: Pair) -> Result<u8> {
+    let literal = pair.as_str();
+
+    if let Ok(value) = literal.parse() {
+        return Ok(value);
+    }
+
+    let (line, col) = pair.as_span().start_pos().line_col();
+    Err(IrError::LiteralOutOfRange {
+        literal: literal.into(),
+        line,
+        col,
+    })
+}
+
 fn ensure_rule(pair: &Pair, rule: Rule) -> Result {
     if pair.as_rule() != rule {
-        Err(format!("BUG: Expected {:?}, found {:?}", rule, pair.as_rule()))?;
+        Err(IrError::UnexpectedRule {
+            expected: rule,
+            found: pair.as_rule(),
+        })?;
     }
 
     Ok(())
@@ -258,4 +773,280 @@ mod tests {
             }
         ").unwrap();
     }
+
+    #[test]
+    fn parse_mul() {
+        let ir = IR::parse_str("let y = x * 2 + 1").unwrap();
+        let var = |name: &str| Box::new(Expr::Var(Ident(name.into())));
+
+        assert_eq!(ir.stmts, [Statement::Decl(Decl {
+            name: Ident("y".into()),
+            value: Some(Expr::Add(
+                Box::new(Expr::Mul(var("x"), Box::new(Expr::Const(2)))),
+                Box::new(Expr::Const(1)),
+            )),
+        })]);
+
+        let ir = IR::parse_str("let y = 3 + 4 * 5").unwrap();
+        match &ir.stmts[0] {
+            Statement::Decl(Decl { value: Some(value), .. }) => assert_eq!(value.const_value(), Some(23)),
+            stmt => panic!("expected a declaration, found {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn parse_comparisons() {
+        let ir = IR::parse_str("let x = a == b > c").unwrap();
+        let var = |name: &str| Box::new(Expr::Var(Ident(name.into())));
+
+        assert_eq!(ir.stmts, [Statement::Decl(Decl {
+            name: Ident("x".into()),
+            value: Some(Expr::Eq(var("a"), Box::new(Expr::Gt(var("b"), var("c"))))),
+        })]);
+
+        let ir = IR::parse_str("let x = 1 + 1 != 2 < 3").unwrap();
+        match &ir.stmts[0] {
+            Statement::Decl(Decl { value: Some(value), .. }) => assert_eq!(value.const_value(), Some(1)),
+            stmt => panic!("expected a declaration, found {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn parse_logical() {
+        let ir = IR::parse_str("let x = a && b || c").unwrap();
+        let var = |name: &str| Box::new(Expr::Var(Ident(name.into())));
+
+        assert_eq!(ir.stmts, [Statement::Decl(Decl {
+            name: Ident("x".into()),
+            value: Some(Expr::Or(Box::new(Expr::And(var("a"), var("b"))), var("c"))),
+        })]);
+
+        let ir = IR::parse_str("let x = a || b && c == d").unwrap();
+
+        assert_eq!(ir.stmts, [Statement::Decl(Decl {
+            name: Ident("x".into()),
+            value: Some(Expr::Or(var("a"), Box::new(Expr::And(var("b"), Box::new(Expr::Eq(var("c"), var("d"))))))),
+        })]);
+    }
+
+    #[test]
+    fn parse_else() {
+        let ir = IR::parse_str("if a { b = 1 } else { b = 2 c = 3 } if a { }").unwrap();
+        let assign = |name: &str, value| Statement::Assign(Assign {
+            name: Ident(name.into()),
+            value: Expr::Const(value),
+        });
+        let cond = Expr::Var(Ident("a".into()));
+
+        assert_eq!(ir.stmts, [
+            Statement::If(If {
+                cond: cond.clone(),
+                body: vec![assign("b", 1)],
+                else_body: vec![assign("b", 2), assign("c", 3)],
+            }),
+            Statement::If(If {
+                cond,
+                body: vec![],
+                else_body: vec![],
+            }),
+        ]);
+    }
+
+    #[test]
+    fn parse_parens() {
+        let value = |src: &str| match IR::parse_str(src).unwrap().stmts.remove(0) {
+            Statement::Decl(Decl { value: Some(value), .. }) => value,
+            stmt => panic!("expected a declaration, found {:?}", stmt),
+        };
+        let var = |name: &str| Box::new(Expr::Var(Ident(name.into())));
+
+        assert_eq!(value("let x = a + b > c"), Expr::Gt(Box::new(Expr::Add(var("a"), var("b"))), var("c")));
+        assert_eq!(value("let x = a + (b > c)"), Expr::Add(var("a"), Box::new(Expr::Gt(var("b"), var("c")))));
+        assert_eq!(value("let x = ((a))"), *var("a"));
+        assert_eq!(value("let x = a + (b && c)"), Expr::Add(var("a"), Box::new(Expr::And(var("b"), var("c")))));
+        assert_eq!(value("let x = (a || b) && c"), Expr::And(Box::new(Expr::Or(var("a"), var("b"))), var("c")));
+        assert_eq!(value("let x = (a + b) * c").const_value(), None);
+        assert_eq!(value("let x = (1 + 2) * 3").const_value(), Some(9));
+    }
+
+    #[test]
+    fn parse_not() {
+        let ir = IR::parse_str("let x = !a && b let y = !(a == 2) let z = !!3").unwrap();
+        let var = |name: &str| Box::new(Expr::Var(Ident(name.into())));
+        let value = |stmt: &Statement| match stmt {
+            Statement::Decl(Decl { value: Some(value), .. }) => value.clone(),
+            stmt => panic!("expected a declaration, found {:?}", stmt),
+        };
+
+        assert_eq!(value(&ir.stmts[0]), Expr::And(Box::new(Expr::Not(var("a"))), var("b")));
+        assert_eq!(value(&ir.stmts[1]), Expr::Not(Box::new(Expr::Eq(var("a"), Box::new(Expr::Const(2))))));
+        assert_eq!(value(&ir.stmts[2]).const_value(), Some(1));
+    }
+
+    #[test]
+    fn parse_comments() {
+        let commented = IR::parse_str("
+            // a counter
+            let x = 1 // start at one
+            /* double it
+               twice */
+            x *= /* inline */ 4
+            // trailing comment").unwrap();
+        let plain = IR::parse_str("let x = 1 x *= 4").unwrap();
+
+        assert_eq!(commented.stmts, plain.stmts);
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let ir = IR::parse_str("
+            #tape 500
+            let a = 1 - (2 - 3) * (4 + 5)
+            let b = !(a > 2) && (a == 3 || 'x' != a) || !a
+            while a < (b == 1) {
+                read b
+                if b { a -= 1 } else {
+                    a += max(a, b + 1)
+                    print_num(a)
+                }
+            }
+            if a {}
+        ").unwrap();
+        let printed = ir.to_string();
+
+        assert_eq!(IR::parse_str(&printed).unwrap(), ir);
+        assert!(printed.starts_with("#tape 500\nlet a = 1 - (2 - 3) * (4 + 5)\n"));
+        assert!(printed.contains("\n    if b {\n        a -= 1\n    } else {\n"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let ir = IR::parse_str("
+            #cells u16
+            let x = 1 + read_num()
+            while x > 2 { x -= 1 }
+        ").unwrap();
+        let json = serde_json::to_string(&ir).unwrap();
+
+        assert!(json.contains(r#"{"name":"x","#));
+        assert_eq!(serde_json::from_str::<IR>(&json).unwrap(), ir);
+    }
+
+    #[test]
+    fn parse_call() {
+        let ir = IR::parse_str("
+            let x = max(3, y + 1)
+            print_num(x)
+            let z = read_num()
+        ").unwrap();
+
+        assert_eq!(ir.stmts.len(), 3);
+
+        match &ir.stmts[1] {
+            Statement::Call(call) => {
+                assert_eq!(&*call.name, "print_num");
+                assert_eq!(call.args.len(), 1);
+            }
+            stmt => panic!("expected a call, found {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn parse_bool() {
+        let ir = IR::parse_str("let b = true let c = false let truthy = b").unwrap();
+
+        assert_eq!(ir.stmts[..2], [
+            Statement::Decl(Decl { name: Ident("b".into()), value: Some(Expr::Const(1)) }),
+            Statement::Decl(Decl { name: Ident("c".into()), value: Some(Expr::Const(0)) }),
+        ]);
+        assert_eq!(ir.stmts[2], Statement::Decl(Decl {
+            name: Ident("truthy".into()),
+            value: Some(Expr::Var(Ident("b".into()))),
+        }));
+        assert!(IR::parse_str("let true = 1").is_err());
+    }
+
+    #[test]
+    fn parse_compound_assign() {
+        let ir = IR::parse_str("x -= 3 x *= y x /= 2").unwrap();
+        let name = || Ident("x".into());
+
+        assert_eq!(ir.stmts, [
+            Statement::SubAssign(SubAssign { name: name(), value: Expr::Const(3) }),
+            Statement::MulAssign(MulAssign { name: name(), value: Expr::Var(Ident("y".into())) }),
+            Statement::DivAssign(DivAssign { name: name(), value: Expr::Const(2) }),
+        ]);
+    }
+
+    #[test]
+    fn parse_break_continue() {
+        let ir = IR::parse_str("while x { if y { break } continue } let breaks = 1").unwrap();
+
+        assert_eq!(ir.stmts[0], Statement::While(While {
+            cond: Expr::Var(Ident("x".into())),
+            body: vec![
+                Statement::If(If {
+                    cond: Expr::Var(Ident("y".into())),
+                    body: vec![Statement::Break],
+                    else_body: vec![],
+                }),
+                Statement::Continue,
+            ],
+        }));
+        assert!(IR::parse_str("let break = 1").is_err());
+    }
+
+    #[test]
+    fn parse_read() {
+        let ir = IR::parse_str("let x read x").unwrap();
+
+        assert_eq!(ir.stmts[1], Statement::Read(Ident("x".into())));
+    }
+
+    #[test]
+    fn literal_out_of_range() {
+        let err = IR::parse_str("let y = 255\nlet x = 300").unwrap_err();
+        assert_eq!(err.to_string(), "literal 300 out of range for u8 cell (max 255) at line 2, column 9");
+    }
+
+    #[test]
+    fn parse_error() {
+        match IR::parse_str("let = 5") {
+            Err(IrError::Parse(_)) => {}
+            result => panic!("expected a parse error, found {:?}", result),
+        }
+
+        match IR::parse_str("#tape 99999999999999999999999") {
+            Err(IrError::Int(_)) => {}
+            result => panic!("expected an int error, found {:?}", result),
+        }
+    }
+
+    #[test]
+    fn parse_error_location() {
+        let err = IR::parse_str("let x = 1\nlet = 5").unwrap_err();
+
+        assert_eq!(err.line_col(), Some((2, 5)));
+        assert!(err.to_string().starts_with("syntax error at line 2, column 5\n"));
+        assert!(err.to_string().contains("let = 5"));
+    }
+
+    #[test]
+    fn parse_directives() {
+        let ir = IR::parse_str("
+            #tape 100
+            #cells u16
+            let x = 1
+        ").unwrap();
+
+        assert_eq!(ir.config, Config {
+            tape_size: 100,
+            cell_width: CellWidth::U16,
+        });
+        assert_eq!(ir.stmts.len(), 1);
+
+        let ir = IR::parse_str("let x = 1").unwrap();
+        assert_eq!(ir.config, Config::default());
+    }
 }
@@ -3,8 +3,12 @@
 use std::sync::{Arc, Weak};
 use std::ops;
 use std::cmp;
+use std::fmt;
 
+pub mod bf;
+pub mod interp;
 pub mod ir;
+pub mod opt;
 pub mod trans;
 
 #[derive(Debug,Clone,PartialEq,PartialOrd)]
@@ -15,16 +19,28 @@ impl Ptr {
         Ptr(Arc::new(addr))
     }
 
-    fn as_isize(&self) -> isize {
+    /// The address of the cell.
+    pub fn as_isize(&self) -> isize {
         *self.0
     }
 
+    /// The pointer `delta` cells away, for addressing cells of a block the caller already owns.
+    /// The stack allocator doesn't know about offset pointers: holding one doesn't keep its cell allocated.
+    pub fn offset(&self, delta: isize) -> Ptr {
+        Ptr::new(self.as_isize() + delta)
+    }
+
+    /// Element `index` of an array allocated with `Context::alloc_indexed_array`.
+    pub fn element(&self, index: usize) -> Ptr {
+        self.offset(INDEXED_STRIDE * (index as isize + 1) + 2)
+    }
+
     fn weak(&self) -> Weak<isize> {
         Arc::downgrade(&self.0)
     }
 }
 
-impl<'a> ops::Add for &'a Ptr {
+impl ops::Add for &Ptr {
     type Output = Ptr;
 
     fn add(self, other: Self) -> Ptr {
@@ -33,7 +49,7 @@ impl<'a> ops::Add for &'a Ptr {
     }
 }
 
-impl<'a> ops::Sub for &'a Ptr {
+impl ops::Sub for &Ptr {
     type Output = Ptr;
 
     fn sub(self, other: Self) -> Ptr {
@@ -42,23 +58,132 @@ impl<'a> ops::Sub for &'a Ptr {
     }
 }
 
-impl<'a> cmp::PartialEq<isize> for &'a Ptr {
+impl cmp::PartialEq<isize> for &Ptr {
     fn eq(&self, addr: &isize) -> bool {
         &self.as_isize() == addr
     }
 }
 
-impl<'a> cmp::PartialOrd<isize> for &'a Ptr {
+impl cmp::PartialOrd<isize> for &Ptr {
     fn partial_cmp(&self, addr: &isize) -> Option<cmp::Ordering> {
         self.as_isize().partial_cmp(addr)
     }
 }
 
+/// One edge of a state machine driven by `Context::run_fsm` or `Context::fsm_step`.
+pub struct Transition<'a> {
+    pub state: u8,
+    pub input: u8,
+    pub next_state: u8,
+    /// Code to run when the transition is taken.
+    pub action: Option<&'a dyn Fn(&mut Context)>,
+}
+
+/// A stack allocated cell that is released when the guard is dropped.
+/// It doesn't borrow the context, so any number of them can be used side by side.
+#[derive(Debug)]
+pub struct AllocGuard(Ptr);
+
+impl ops::Deref for AllocGuard {
+    type Target = Ptr;
+
+    fn deref(&self) -> &Ptr {
+        &self.0
+    }
+}
+
+/// A fixed number of cells used as a FIFO queue.
+/// Pushing into a full buffer overwrites the oldest value, popping an empty one yields garbage.
+pub struct RingBuffer {
+    slots: Vec<Ptr>,
+    /// Index of the next slot to pop.
+    head: Ptr,
+    /// Index of the next slot to push.
+    tail: Ptr,
+}
+
+impl RingBuffer {
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+/// Cells per element of an indexed array.
+const INDEXED_STRIDE: isize = 3;
+
+/// Optimal sorting networks for up to 8 cells, indexed by the number of cells.
+const SORTING_NETWORKS: [&[(usize, usize)]; 9] = [
+    &[],
+    &[],
+    &[(0, 1)],
+    &[(0, 2), (0, 1), (1, 2)],
+    &[(0, 1), (2, 3), (0, 2), (1, 3), (1, 2)],
+    &[(0, 3), (1, 4), (0, 2), (1, 3), (0, 1), (2, 4), (1, 2), (3, 4), (2, 3)],
+    &[(0, 5), (1, 3), (2, 4), (1, 2), (3, 4), (0, 3), (2, 5), (0, 1), (2, 3), (4, 5), (1, 2), (3, 4)],
+    &[
+        (0, 6), (2, 3), (4, 5), (0, 2), (1, 4), (3, 6), (0, 1), (2, 5),
+        (3, 4), (1, 2), (4, 6), (2, 3), (4, 5), (1, 2), (3, 4), (5, 6),
+    ],
+    &[
+        (0, 2), (1, 3), (4, 6), (5, 7), (0, 4), (1, 5), (2, 6), (3, 7), (0, 1), (2, 3),
+        (4, 5), (6, 7), (2, 4), (3, 5), (1, 4), (3, 6), (1, 2), (3, 4), (5, 6),
+    ],
+];
+
+/// Counts of the commands a context has emitted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub increments: usize,
+    pub decrements: usize,
+    pub moves_left: usize,
+    pub moves_right: usize,
+    pub loop_starts: usize,
+    pub loop_ends: usize,
+}
+
+const WRITER_KEEPS_NO_CODE: &str = "a context from `with_writer` doesn't keep its code";
+
+/// Where a context writes its code.
+enum Sink<'c> {
+    Borrowed(&'c mut String),
+    Owned(String),
+    Writer(&'c mut dyn fmt::Write),
+}
+
+impl Sink<'_> {
+    fn push_str(&mut self, code: &str) {
+        match self {
+            Sink::Borrowed(buf) => buf.push_str(code),
+            Sink::Owned(buf) => buf.push_str(code),
+            Sink::Writer(writer) => writer.write_str(code).expect("failed to write code"),
+        }
+    }
+
+    fn get(&self) -> &str {
+        match self {
+            Sink::Borrowed(code) => code,
+            Sink::Owned(code) => code,
+            Sink::Writer(_) => panic!("{}", WRITER_KEEPS_NO_CODE),
+        }
+    }
+}
+
 pub struct Context<'c> {
-    code: &'c mut String,
+    code: Sink<'c>,
     addr: isize,
+    max_addr: isize,
     stack_pointers: Vec<Weak<isize>>,
     known_values: Vec<Option<u8>>,
+    print_register: Option<Ptr>,
+    emitted_len: usize,
+    stats: Stats,
+}
+
+impl Context<'static> {
+    /// A context that keeps its code itself, see `finish`.
+    pub fn owned() -> Self {
+        Self::with_sink(Sink::Owned(String::new()), 0)
+    }
 }
 
 impl<'c> Context<'c> {
@@ -67,11 +192,39 @@ impl<'c> Context<'c> {
     }
 
     pub fn with_addr(code: &'c mut String, addr: isize) -> Self {
+        Self::with_sink(Sink::Borrowed(code), addr)
+    }
+
+    /// A context that writes its code straight into `writer` instead of keeping it.
+    /// Panics if writing fails.
+    pub fn with_writer<W: fmt::Write>(writer: &'c mut W) -> Self {
+        Self::with_sink(Sink::Writer(writer), 0)
+    }
+
+    fn with_sink(code: Sink<'c>, addr: isize) -> Self {
         Self {
             code,
             addr,
+            max_addr: addr,
             stack_pointers: Vec::new(),
             known_values: Vec::new(),
+            print_register: None,
+            emitted_len: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    /// A context that continues from the current state but emits into `code`.
+    fn fork<'a>(&self, code: &'a mut String) -> Context<'a> {
+        Context {
+            code: Sink::Borrowed(code),
+            addr: self.addr,
+            max_addr: self.max_addr,
+            stack_pointers: self.stack_pointers.clone(),
+            known_values: self.known_values.clone(),
+            print_register: self.print_register.clone(),
+            emitted_len: self.emitted_len,
+            stats: self.stats.clone(),
         }
     }
 
@@ -89,10 +242,12 @@ impl<'c> Context<'c> {
             return;
         }
 
-        self.known_values
+        if let Some(value) = self.known_values
             .get_mut(ptr.as_isize() as usize)
             .and_then(|value| value.as_mut())
-            .map(|value| *value = f(*value));
+        {
+            *value = f(*value);
+        }
     }
 
     pub fn assume(&mut self, ptr: &Ptr, value: u8) {
@@ -126,14 +281,19 @@ impl<'c> Context<'c> {
             .and_then(|value| *value)
     }
 
+    /// Known value of a boolean cell: 0 is false, anything else is true.
+    pub fn value_bool(&self, ptr: &Ptr) -> Option<bool> {
+        self.value(ptr).map(|value| value != 0)
+    }
+
     pub fn forget(&mut self, ptr: &Ptr) {
         if ptr < 0 {
             return;
         }
 
-        self.known_values
-            .get_mut(ptr.as_isize() as usize)
-            .map(|value| *value = None);
+        if let Some(value) = self.known_values.get_mut(ptr.as_isize() as usize) {
+            *value = None;
+        }
     }
 
     pub fn stack_alloc(&mut self) -> Ptr {
@@ -152,6 +312,63 @@ impl<'c> Context<'c> {
         }
     }
 
+    /// Allocates `len` consecutive cells and returns a pointer to the first one.
+    /// The other cells are reached with `Ptr::offset` and stay allocated as long as the returned pointer lives.
+    pub fn alloc_array(&mut self, len: usize) -> Ptr {
+        assert!(len > 0, "arrays need at least one cell");
+
+        let is_free = |slot: &Weak<isize>| slot.upgrade().is_none();
+        let start = (0..=self.stack_pointers.len())
+            .find(|&start| self.stack_pointers.iter().skip(start).take(len).all(is_free))
+            .unwrap();
+
+        let base = Ptr::new(start as isize);
+
+        for addr in start..start + len {
+            match self.stack_pointers.get_mut(addr) {
+                Some(slot) => *slot = base.weak(),
+                None => self.stack_pointers.push(base.weak()),
+            }
+        }
+
+        base
+    }
+
+    /// Allocates an array of `len` elements that can be indexed at runtime with `array_get`.
+    /// Its elements are reached with `Ptr::element`.
+    ///
+    /// Every element takes three cells: a marker, a carry and the data itself.
+    /// The first three cells belong to no element; only the first of them is used,
+    /// as the zero that stops the walk back to the start:
+    ///
+    /// ```text
+    /// base: [0] [-] [-] [m0] [c0] [d0] [m1] [c1] [d1] ...
+    /// ```
+    ///
+    /// Markers and carries are zero between operations.
+    pub fn alloc_indexed_array(&mut self, len: usize) -> Ptr {
+        let base = self.alloc_array(INDEXED_STRIDE as usize * (len + 1));
+        self.clear(&base);
+
+        for i in 0..len {
+            let data = base.element(i);
+            self.clear(&data.offset(-2));
+            self.clear(&data.offset(-1));
+        }
+
+        base
+    }
+
+    /// Allocates a cell for as long as the returned guard lives.
+    pub fn alloc(&mut self) -> AllocGuard {
+        AllocGuard(self.stack_alloc())
+    }
+
+    /// Number of cells the stack allocator has handed out so far.
+    pub fn stack_size(&self) -> usize {
+        self.stack_pointers.len()
+    }
+
     pub fn with_stack_alloc<F> (&mut self, f: F)
     where
         F: FnOnce(&mut Context, &Ptr)
@@ -207,10 +424,31 @@ impl<'c> Context<'c> {
     fn seek(&mut self, ptr: &Ptr) {
         let offset = ptr.as_isize() - self.addr;
         let direction = if offset.is_positive() { ">" } else { "<" };
-        let offset = offset.abs() as usize;
+        let offset = offset.unsigned_abs();
 
         self.emit(&direction.repeat(offset));
         self.addr = ptr.as_isize();
+        self.max_addr = self.max_addr.max(self.addr);
+    }
+
+    /// Seeks to `from` and emits `code`, a walk whose moves depend on the tape,
+    /// like `[>]` to the next zero cell and `[<]` back. The walk must end at `to`.
+    /// Which cells it passes is only known at runtime, so it forgets all known values,
+    /// and of its moves only `from` and `to` count towards `max_addr`.
+    pub fn walk(&mut self, from: &Ptr, code: &str, to: &Ptr) {
+        self.seek(from);
+        self.emit(code);
+        self.addr = to.as_isize();
+        self.max_addr = self.max_addr.max(self.addr);
+        self.forget_known_values();
+    }
+
+    /// Moves the head `n` cells to the right without touching any cell.
+    /// This only pads the code; it doesn't reserve the skipped cells for the stack allocator.
+    pub fn skip_cells(&mut self, n: usize) {
+        self.emit(&">".repeat(n));
+        self.addr += n as isize;
+        self.max_addr = self.max_addr.max(self.addr);
     }
 
     pub fn clear(&mut self, ptr: &Ptr) {
@@ -233,13 +471,26 @@ impl<'c> Context<'c> {
         self.increment_by(ptr, value);
     }
 
+    pub fn clear_range(&mut self, start: &Ptr, len: usize) {
+        for i in 0..len {
+            self.clear(&start.offset(i as isize));
+        }
+    }
+
+    /// Sets the `len` cells starting at `start` to value.
+    pub fn fill(&mut self, start: &Ptr, len: usize, value: u8) {
+        for i in 0..len {
+            self.set(&start.offset(i as isize), value);
+        }
+    }
+
     pub fn set_bool(&mut self, ptr: &Ptr, value: bool) {
         debug_assert!(false as u8 == 0);
         debug_assert!(true as u8 == 1);
 
-        match self.value(ptr) {
-            Some(0) => self.increment(ptr),
-            Some(1) => self.decrement(ptr),
+        match (self.value(ptr), value) {
+            (Some(0), true) => self.increment(ptr),
+            (Some(1), false) => self.decrement(ptr),
             _ => self.set(ptr, value as u8),
         }
     }
@@ -249,34 +500,298 @@ impl<'c> Context<'c> {
         self.emit(".");
     }
 
+    /// Prints '1' if *ptr is not zero and '0' otherwise.
+    pub fn print_bool(&mut self, ptr: &Ptr) {
+        self.with_stack_alloc(|ctx, digit| {
+            match ctx.value_bool(ptr) {
+                Some(value) => ctx.set(digit, b'0' + value as u8),
+                None => {
+                    ctx.is_not_zero(ptr, digit);
+                    ctx.increment_by(digit, b'0');
+                }
+            }
+
+            ctx.print(digit);
+        })
+    }
+
+    /// Prints the `count` cells from `start` on as a string of '0' and '1'.
+    /// `start` holds the most significant bit and is printed first.
+    pub fn print_bits(&mut self, start: &Ptr, count: usize) {
+        for i in 0..count {
+            self.print_bool(&start.offset(i as isize));
+        }
+    }
+
+    /// Prints *ptr in decimal, without leading zeros.
+    pub fn print_u8_decimal(&mut self, ptr: &Ptr) {
+        if let Some(value) = self.value(ptr) {
+            self.print_str(&value.to_string());
+            return;
+        }
+
+        self.with_stack_alloc5(|ctx, ten, rest, ones, tens, hundreds| {
+            ctx.set(ten, 10);
+            ctx.div_mod(ptr, ten, rest, ones);
+            ctx.div_mod(rest, ten, hundreds, tens);
+
+            // `rest` and `ten` are done, so they turn into the flags for the leading digits.
+            let (print_hundreds, print_tens) = (rest, ten);
+            ctx.is_not_zero(hundreds, print_hundreds);
+            ctx.is_not_zero(tens, print_tens);
+            ctx.or_assign(print_hundreds, print_tens);
+
+            ctx.iff_destructive(print_hundreds, |ctx| ctx.print_digit(hundreds));
+            ctx.iff_destructive(print_tens, |ctx| ctx.print_digit(tens));
+            ctx.print_digit(ones);
+        })
+    }
+
+    /// Prints a single digit cell as its ASCII character.
+    /// Sideffect: *digit += '0'
+    fn print_digit(&mut self, digit: &Ptr) {
+        self.num_to_digit(digit);
+        self.print(digit);
+    }
+
+    pub fn print_newline(&mut self) {
+        self.print_str("\n");
+    }
+
+    /// Prints *from down to 1 in decimal, one number per line.
+    /// Sideffect: *from = 0
+    pub fn print_countdown(&mut self, from: &Ptr) {
+        self.repeat_reverse_destructive(from, |ctx, counter| {
+            ctx.print_u8_decimal(counter);
+            ctx.print_newline();
+        });
+    }
+
+    /// Prints `text` through a cell that is kept across calls, so each character
+    /// only costs the distance from the previously printed one.
+    pub fn print_str(&mut self, text: &str) {
+        let register = match &self.print_register {
+            Some(register) => register.clone(),
+            None => {
+                let register = self.stack_alloc();
+                self.print_register = Some(register.clone());
+                register
+            }
+        };
+
+        self.print_str_using(&register, text);
+    }
+
+    /// Prints `text` using `scratch` as the cell that holds each byte.
+    /// Consecutive bytes are reached from the previous one, so `scratch` isn't cleared for every byte.
+    pub fn print_str_using(&mut self, scratch: &Ptr, text: &str) {
+        for &byte in text.as_bytes() {
+            self.adjust(scratch, byte);
+            self.print(scratch);
+        }
+    }
+
+    /// Brings `ptr` to `value` with whatever is shortest:
+    /// stepping up or down from its known value, or clearing it and counting up.
+    fn adjust(&mut self, ptr: &Ptr, value: u8) {
+        let current = match self.value(ptr) {
+            Some(current) => current,
+            None => return self.set(ptr, value),
+        };
+        let up = value.wrapping_sub(current);
+        let down = up.wrapping_neg();
+        let reset = "[-]".len() + value as usize;
+
+        if reset < up.min(down) as usize {
+            self.set(ptr, value);
+        } else if up <= down {
+            self.increment_by(ptr, up);
+        } else {
+            self.decrement_by(ptr, down);
+        }
+    }
+
     pub fn read(&mut self, ptr: &Ptr) {
         self.seek(ptr);
         self.forget(ptr);
         self.emit(",");
     }
 
+    /// Reads a decimal number into target, stopping at the first byte that is not a digit.
+    /// That byte is consumed. Numbers above 255 wrap around.
+    pub fn read_u8_decimal(&mut self, target: &Ptr) {
+        self.with_stack_alloc2(|ctx, byte, is_digit| {
+            ctx.clear(target);
+            ctx.read(byte);
+            ctx.is_digit(byte, is_digit);
+
+            ctx.while_true(is_digit, |ctx| {
+                ctx.mul_const(target, 10);
+                ctx.digit_to_num(byte);
+                ctx.add(target, byte);
+                ctx.read(byte);
+                ctx.is_digit(byte, is_digit);
+            });
+        })
+    }
+
+    /// Like `read_u8_decimal`, but reads up to a newline or the end of input
+    /// and sets ok to whether everything before it was a digit.
+    /// Empty input is not ok either.
+    pub fn read_u8_decimal_checked(&mut self, target: &Ptr, ok: &Ptr) {
+        assert_ne!(target, ok);
+
+        self.with_stack_alloc4(|ctx, byte, more, is_digit, any_digit| {
+            ctx.clear(target);
+            ctx.set_bool(ok, true);
+            ctx.set_bool(any_digit, false);
+            ctx.read(byte);
+            ctx.is_not_terminator(byte, more);
+
+            ctx.while_true(more, |ctx| {
+                ctx.is_digit(byte, is_digit);
+                ctx.if_else(is_digit, |ctx| {
+                    ctx.mul_const(target, 10);
+                    ctx.digit_to_num(byte);
+                    ctx.add(target, byte);
+                    ctx.set_bool(any_digit, true);
+                }, |ctx| {
+                    ctx.set_bool(ok, false);
+                });
+
+                ctx.read(byte);
+                ctx.is_not_terminator(byte, more);
+            });
+
+            ctx.and_assign(any_digit, ok);
+        })
+    }
+
+    /// target = *value is neither a newline nor zero, which `,` reads at the end of input;
+    fn is_not_terminator(&mut self, value: &Ptr, target: &Ptr) {
+        self.with_stack_alloc(|ctx, newline| {
+            ctx.set(newline, b'\n');
+            ctx.not_equals(value, newline, target);
+            ctx.is_not_zero(value, newline);
+            ctx.and_assign(newline, target);
+        })
+    }
+
+    /// target = *value is an ASCII digit;
+    fn is_digit(&mut self, value: &Ptr, target: &Ptr) {
+        self.with_stack_alloc2(|ctx, bound, below_nine| {
+            ctx.set(bound, b'0' - 1);
+            ctx.greater_than(value, bound, target);
+            ctx.set(bound, b'9' + 1);
+            ctx.less_than(value, bound, below_nine);
+            ctx.and_assign(below_nine, target);
+        })
+    }
+
+    pub fn alloc_ring_buffer(&mut self, capacity: u8) -> RingBuffer {
+        assert!(capacity > 0, "ring buffers need at least one slot");
+
+        let ring = RingBuffer {
+            slots: (0..capacity).map(|_| self.stack_alloc()).collect(),
+            head: self.stack_alloc(),
+            tail: self.stack_alloc(),
+        };
+
+        self.clear(&ring.head);
+        self.clear(&ring.tail);
+
+        ring
+    }
+
+    pub fn ring_push(&mut self, ring: &RingBuffer, value: &Ptr) {
+        self.with_slot(&ring.tail, &ring.slots, |ctx, slot| ctx.copy(value, slot));
+        self.advance_ring_index(&ring.tail, ring.capacity());
+    }
+
+    /// Moves the oldest value of the ring into target.
+    pub fn ring_pop(&mut self, ring: &RingBuffer, target: &Ptr) {
+        self.clear(target);
+        self.with_slot(&ring.head, &ring.slots, |ctx, slot| ctx.mov(target, slot));
+        self.advance_ring_index(&ring.head, ring.capacity());
+    }
+
+    /// Runs `f` on the slot that `*index` refers to.
+    /// The slot is only known at runtime, so `f` is emitted once per slot.
+    fn with_slot<F>(&mut self, index: &Ptr, slots: &[Ptr], f: F)
+    where
+        F: Fn(&mut Context, &Ptr),
+    {
+        for (i, slot) in slots.iter().enumerate() {
+            self.with_stack_alloc2(|ctx, i_ptr, is_index| {
+                ctx.set(i_ptr, i as u8);
+                ctx.equals(index, i_ptr, is_index);
+                ctx.iff_destructive(is_index, |ctx| f(ctx, slot));
+            })
+        }
+    }
+
+    fn advance_ring_index(&mut self, index: &Ptr, capacity: usize) {
+        self.increment(index);
+
+        self.with_stack_alloc2(|ctx, capacity_ptr, wrapped| {
+            ctx.set(capacity_ptr, capacity as u8);
+            ctx.equals(index, capacity_ptr, wrapped);
+            ctx.iff_destructive(wrapped, |ctx| ctx.clear(index));
+        })
+    }
+
+    /// Copies input to output until EOF (the `cat` program).
+    /// Assumes the interpreter reads EOF as 0; a NUL byte in the input also stops the loop.
+    pub fn echo_loop(&mut self) {
+        self.with_stack_alloc(|ctx, byte| {
+            ctx.read(byte);
+            ctx.while_not_zero(byte, |ctx| {
+                ctx.print(byte);
+                ctx.read(byte);
+            });
+        })
+    }
+
     pub fn increment(&mut self, ptr: &Ptr) {
         self.seek(ptr);
         self.emit("+");
-        self.map_known_value(ptr, |v| v + 1)
+        self.map_known_value(ptr, |v| v.wrapping_add(1))
     }
 
     pub fn increment_by(&mut self, ptr: &Ptr, amount: u8) {
         self.seek(ptr);
         self.emit(&"+".repeat(amount as usize));
-        self.map_known_value(ptr, |v| v + amount)
+        self.map_known_value(ptr, |v| v.wrapping_add(amount))
     }
 
     pub fn decrement(&mut self, ptr: &Ptr) {
         self.seek(ptr);
         self.emit("-");
-        self.map_known_value(ptr, |v| v - 1)
+        self.map_known_value(ptr, |v| v.wrapping_sub(1))
     }
 
     pub fn decrement_by(&mut self, ptr: &Ptr, amount: u8) {
         self.seek(ptr);
         self.emit(&"-".repeat(amount as usize));
-        self.map_known_value(ptr, |v| v - amount)
+        self.map_known_value(ptr, |v| v.wrapping_sub(amount))
+    }
+
+    /// Turns an ASCII digit ('0'..='9') into its value.
+    pub fn digit_to_num(&mut self, ptr: &Ptr) {
+        if let Some(value) = self.value(ptr) {
+            debug_assert!(value.is_ascii_digit(), "expected an ASCII digit, found {}", value);
+        }
+
+        self.decrement_by(ptr, b'0');
+    }
+
+    /// Turns a value in 0..=9 into its ASCII digit.
+    pub fn num_to_digit(&mut self, ptr: &Ptr) {
+        if let Some(value) = self.value(ptr) {
+            debug_assert!(value <= 9, "expected a number below 10, found {}", value);
+        }
+
+        self.increment_by(ptr, b'0');
     }
 
     pub fn iff<F>(&mut self, cond: &Ptr, f: F)
@@ -324,6 +839,23 @@ impl<'c> Context<'c> {
         });
     }
 
+    /// Like `if_else`, but uses up `cond` instead of copying it.
+    /// Sideffect: *cond = 0
+    pub fn if_else_consume<F, G>(&mut self, cond: Ptr, f: F, g: G)
+    where
+        F: FnOnce(&mut Context),
+        G: FnOnce(&mut Context),
+    {
+        self.with_stack_alloc(|ctx, run_else| {
+            ctx.set_bool(run_else, true);
+            ctx.iff_destructive(&cond, |ctx| {
+                ctx.clear(run_else);
+                f(ctx);
+            });
+            ctx.iff_destructive(run_else, g);
+        });
+    }
+
     pub fn while_not_zero<F>(&mut self, ptr: &Ptr, f: F)
     where
         F: FnOnce(&mut Context),
@@ -334,6 +866,37 @@ impl<'c> Context<'c> {
         f(self);
         self.seek(ptr);
         self.emit("]");
+        // The body may have run any number of times, including zero.
+        self.forget_known_values();
+        self.assume(ptr, 0);
+    }
+
+    /// Like `while_not_zero`, but keeps the known values of `preserved`.
+    /// The caller promises that `f` doesn't modify any of the preserved cells;
+    /// if it does, later code is generated from wrong values.
+    pub fn while_not_zero_preserving<F>(&mut self, ptr: &Ptr, preserved: &[Ptr], f: F)
+    where
+        F: FnOnce(&mut Context),
+    {
+        let known = preserved.iter()
+            .filter_map(|cell| Some((cell, self.value(cell)?)))
+            .collect::<Vec<_>>();
+        let restore = |ctx: &mut Context| {
+            for &(cell, value) in &known {
+                ctx.assume(cell, value);
+            }
+        };
+
+        self.seek(ptr);
+        self.emit("[");
+        self.forget_known_values();
+        restore(self);
+        f(self);
+        self.seek(ptr);
+        self.emit("]");
+        self.forget_known_values();
+        restore(self);
+        self.assume(ptr, 0);
     }
 
     pub fn while_true<F>(&mut self, cond: &Ptr, f: F)
@@ -343,6 +906,30 @@ impl<'c> Context<'c> {
         self.while_not_zero(cond, f);
     }
 
+    /// Runs `f` while `*cond` is not zero, but at most `max` times.
+    pub fn bounded_loop<F>(&mut self, cond: &Ptr, max: u8, f: F)
+    where
+        F: FnOnce(&mut Context),
+    {
+        let check = |ctx: &mut Context, counter: &Ptr, go: &Ptr| {
+            ctx.is_not_zero(cond, go);
+            ctx.with_stack_alloc(|ctx, counter_left| {
+                ctx.is_not_zero(counter, counter_left);
+                ctx.and_assign(counter_left, go);
+            });
+        };
+
+        self.with_stack_alloc2(|ctx, counter, go| {
+            ctx.set(counter, max);
+            check(ctx, counter, go);
+
+            ctx.while_true(go, |ctx| {
+                f(ctx);
+                ctx.decrement(counter);
+                check(ctx, counter, go);
+            });
+        })
+    }
 
     /// Runs the code emitted by `f` `*ptr` many times.
     /// Sideffect: *ptr = 0
@@ -371,6 +958,12 @@ impl<'c> Context<'c> {
     pub fn add(&mut self, target: &Ptr, source: &Ptr) {
         assert_ne!(source, target);
 
+        if let Some(amount) = self.value(source) {
+            self.increment_by(target, amount);
+            self.clear(source);
+            return;
+        }
+
         self.repeat_reverse_destructive(source, |ctx, _| {
             ctx.increment(target);
         });
@@ -380,11 +973,81 @@ impl<'c> Context<'c> {
     pub fn sub(&mut self, target: &Ptr, source: &Ptr) {
         assert_ne!(source, target);
 
+        if let Some(amount) = self.value(source) {
+            self.decrement_by(target, amount);
+            self.clear(source);
+            return;
+        }
+
         self.repeat_reverse_destructive(source, |ctx, _| {
             ctx.decrement(target);
         });
     }
 
+    /// target = min(255, target + source);
+    /// Unlike `add`, source is preserved.
+    pub fn saturating_add(&mut self, target: &Ptr, source: &Ptr) {
+        assert_ne!(source, target);
+
+        if let (Some(target_value), Some(amount)) = (self.value(target), self.value(source)) {
+            self.set(target, target_value.saturating_add(amount));
+            return;
+        }
+
+        self.with_stack_alloc3(|ctx, room, tmp, overflows| {
+            // Adding source overflows exactly when source > 255 - target.
+            ctx.set(room, 255);
+            ctx.copy(target, tmp);
+            ctx.sub(room, tmp);
+            ctx.greater_than(source, room, overflows);
+
+            ctx.if_else(overflows, |ctx| {
+                ctx.set(target, 255);
+            }, |ctx| {
+                ctx.copy(source, tmp);
+                ctx.add(target, tmp);
+            });
+        })
+    }
+
+    /// target = max(0, target - source);
+    /// Unlike `sub`, source is preserved.
+    pub fn saturating_sub(&mut self, target: &Ptr, source: &Ptr) {
+        assert_ne!(source, target);
+
+        if let (Some(target_value), Some(amount)) = (self.value(target), self.value(source)) {
+            self.set(target, target_value.saturating_sub(amount));
+            return;
+        }
+
+        self.with_stack_alloc2(|ctx, tmp, underflows| {
+            ctx.greater_than(source, target, underflows);
+
+            ctx.if_else(underflows, |ctx| {
+                ctx.clear(target);
+            }, |ctx| {
+                ctx.copy(source, tmp);
+                ctx.sub(target, tmp);
+            });
+        })
+    }
+
+    /// *ptr = -*ptr; (mod 256)
+    pub fn negate(&mut self, ptr: &Ptr) {
+        if let Some(value) = self.value(ptr) {
+            self.set(ptr, value.wrapping_neg());
+            return;
+        }
+
+        self.with_stack_alloc(|ctx, tmp| {
+            ctx.clear(tmp);
+            ctx.repeat_reverse_destructive(ptr, |ctx, _| {
+                ctx.decrement(tmp);
+            });
+            ctx.mov(ptr, tmp);
+        })
+    }
+
     /// target = target * source;
     pub fn mul(&mut self, target: &Ptr, source: &Ptr) {
         assert_ne!(source, target);
@@ -401,40 +1064,287 @@ impl<'c> Context<'c> {
         })
     }
 
-    pub fn mov(&mut self, target: &Ptr, source: &Ptr) {
-        if source == target {
+    /// target = *a * *b;
+    /// Shift-and-add multiplication. The runtime still depends on the operands,
+    /// but is bounded by about 8 * 255 loop iterations instead of growing with a * b like `mul`.
+    pub fn mul_binary(&mut self, a: &Ptr, b: &Ptr, target: &Ptr) {
+        assert_ne!(a, target);
+        assert_ne!(b, target);
+
+        if let (Some(a), Some(b)) = (self.value(a), self.value(b)) {
+            self.set(target, a.wrapping_mul(b));
             return;
         }
 
-        self.clear(target);
+        self.with_stack_alloc4(|ctx, shifted, rest, bit, tmp| {
+            ctx.copy(a, shifted);
+            ctx.copy(b, rest);
+            ctx.clear(target);
 
-        self.while_not_zero(source, |ctx| {
-            ctx.increment(target);
-            ctx.decrement(source);
+            for _ in 0..8 {
+                ctx.halve(rest, bit);
+                ctx.iff_destructive(bit, |ctx| {
+                    ctx.copy(shifted, tmp);
+                    ctx.add(target, tmp);
+                });
+                ctx.mul_const(shifted, 2);
+            }
         })
     }
 
-    pub fn is_zero_destructive(&mut self, value: &Ptr) {
-        self.with_stack_alloc(|ctx, is_zero| {
-            ctx.set_bool(is_zero, true);
-
-            ctx.while_not_zero(value, |ctx| {
-                ctx.assume_bool(is_zero, true);
-                ctx.set_bool(is_zero, false);
-                ctx.set_bool(value, false);
-            });
+    /// *ptr = *ptr * factor;
+    pub fn mul_const(&mut self, ptr: &Ptr, factor: u8) {
+        if let Some(value) = self.value(ptr) {
+            self.set(ptr, value.wrapping_mul(factor));
+            return;
+        }
 
-            ctx.iff_destructive(is_zero, |ctx| {
-                ctx.assume_bool(value, false);
-                ctx.set_bool(value, true);
-            })
-        })
+        match factor {
+            0 => self.clear(ptr),
+            1 => {},
+            _ => self.with_stack_alloc(|ctx, tmp| {
+                ctx.mov(tmp, ptr);
+                ctx.repeat_reverse_destructive(tmp, |ctx, _| {
+                    ctx.increment_by(ptr, factor);
+                });
+            }),
+        }
     }
 
-    pub fn is_zero(&mut self, source: &Ptr, target: &Ptr) {
-        self.copy(source, target);
-        self.is_zero_destructive(target);
-    }
+    /// target = target * source; source = 0;
+    pub fn mul_assign(&mut self, target: &Ptr, source: &Ptr) {
+        assert_ne!(source, target);
+
+        if let (Some(target_val), Some(source_val)) = (self.value(target), self.value(source)) {
+            self.set(target, target_val.wrapping_mul(source_val));
+            self.clear(source);
+            return;
+        }
+
+        self.with_stack_alloc(|ctx, product| {
+            ctx.clear(product);
+
+            ctx.repeat_reverse_destructive(source, |ctx, _| {
+                ctx.repeat_reverse(target, |ctx, _| {
+                    ctx.increment(product);
+                });
+            });
+
+            ctx.mov(target, product);
+        })
+    }
+
+    /// quotient = dividend / divisor; remainder = dividend % divisor;
+    /// Dividing by zero leaves both quotient and remainder at 0.
+    pub fn div_mod(&mut self, dividend: &Ptr, divisor: &Ptr, quotient: &Ptr, remainder: &Ptr) {
+        for operand in &[dividend, divisor] {
+            assert_ne!(*operand, quotient);
+            assert_ne!(*operand, remainder);
+        }
+        assert_ne!(quotient, remainder);
+
+        if let (Some(dividend), Some(divisor)) = (self.value(dividend), self.value(divisor)) {
+            let (q, r) = dividend.checked_div(divisor)
+                .map_or((0, 0), |q| (q, dividend % divisor));
+            self.set(quotient, q);
+            self.set(remainder, r);
+            return;
+        }
+
+        // Counting the dividend down takes linear time, subtracting the divisor over and over quadratic.
+        if let Some(divisor) = self.value(divisor).filter(|&divisor| divisor != 0) {
+            self.with_stack_alloc(|ctx, wrapped| {
+                ctx.clear(quotient);
+                ctx.clear(remainder);
+
+                ctx.repeat_reverse(dividend, |ctx, _| {
+                    ctx.increment(remainder);
+
+                    ctx.copy(remainder, wrapped);
+                    ctx.decrement_by(wrapped, divisor);
+                    ctx.is_zero_destructive(wrapped);
+                    ctx.iff_destructive(wrapped, |ctx| {
+                        ctx.clear(remainder);
+                        ctx.increment(quotient);
+                    });
+                });
+            });
+            return;
+        }
+
+        self.with_stack_alloc2(|ctx, fits, tmp| {
+            ctx.clear(quotient);
+            ctx.copy(dividend, remainder);
+
+            ctx.divisor_fits(divisor, remainder, fits);
+            ctx.while_true(fits, |ctx| {
+                ctx.copy(divisor, tmp);
+                ctx.sub(remainder, tmp);
+                ctx.increment(quotient);
+                ctx.divisor_fits(divisor, remainder, fits);
+            });
+
+            ctx.is_zero(divisor, tmp);
+            ctx.iff_destructive(tmp, |ctx| {
+                ctx.clear(remainder);
+            });
+        })
+    }
+
+    /// target = a % b; target = 0 if b is 0.
+    pub fn modulo(&mut self, a: &Ptr, b: &Ptr, target: &Ptr) {
+        assert_ne!(a, target);
+        assert_ne!(b, target);
+
+        if let (Some(a), Some(b)) = (self.value(a), self.value(b)) {
+            self.set(target, a.checked_rem(b).unwrap_or(0));
+            return;
+        }
+
+        self.with_stack_alloc(|ctx, fits| {
+            ctx.copy(a, target);
+
+            ctx.divisor_fits(b, target, fits);
+            ctx.while_true(fits, |ctx| {
+                ctx.repeat_reverse(b, |ctx, _| ctx.decrement(target));
+                ctx.divisor_fits(b, target, fits);
+            });
+
+            ctx.is_zero(b, fits);
+            ctx.iff_destructive(fits, |ctx| ctx.clear(target));
+        })
+    }
+
+    /// fits = *divisor != 0 && *remainder >= *divisor;
+    fn divisor_fits(&mut self, divisor: &Ptr, remainder: &Ptr, fits: &Ptr) {
+        self.with_stack_alloc(|ctx, divisor_not_zero| {
+            ctx.greater_than(divisor, remainder, fits);
+            ctx.not(fits);
+            ctx.is_not_zero(divisor, divisor_not_zero);
+            ctx.and_assign(divisor_not_zero, fits);
+        })
+    }
+
+    /// out = number of cells from start up to the first zero cell.
+    /// The string is left intact. The cells from start up to and including
+    /// the terminator must not overlap any stack allocation.
+    pub fn strlen(&mut self, start: &Ptr, out: &Ptr) {
+        self.with_stack_alloc3(|ctx, first, non_empty, shifted| {
+            ctx.clear(out);
+            ctx.clear(shifted);
+
+            // With the first character set aside, `start` is a zero cell
+            // that the walks below can find their way back to.
+            ctx.mov(first, start);
+            ctx.is_not_zero(first, non_empty);
+
+            ctx.iff_destructive(non_empty, |ctx| {
+                ctx.increment(out);
+
+                // Move the last character one cell to the right, over the terminator,
+                // until only a gap is left at start + 1.
+                ctx.while_not_zero(&start.offset(1), |ctx| {
+                    ctx.walk(&start.offset(1), "[>]<[->+<]<[<]", start);
+                    ctx.increment(out);
+                    ctx.increment(shifted);
+                });
+
+                // Move every shifted character back by closing the gap from the left.
+                ctx.repeat_reverse_destructive(shifted, |ctx, _| {
+                    ctx.walk(start, ">[>]>[-<+>]<[<]", start);
+                });
+            });
+
+            ctx.mov(start, first);
+        })
+    }
+
+    /// Copies source into every target, draining source only once.
+    pub fn copy_to_all(&mut self, source: &Ptr, targets: &[&Ptr]) {
+        for (i, target) in targets.iter().enumerate() {
+            assert_ne!(source, *target);
+            assert!(!targets[..i].contains(target), "copy_to_all targets must be disjoint");
+        }
+
+        if let Some(value) = self.value(source) {
+            for target in targets {
+                self.set(target, value);
+            }
+            return;
+        }
+
+        self.with_stack_alloc(|ctx, tmp| {
+            for target in targets {
+                ctx.clear(target);
+            }
+
+            ctx.mov(tmp, source);
+            ctx.repeat_reverse_destructive(tmp, |ctx, _| {
+                ctx.increment(source);
+
+                for target in targets {
+                    ctx.increment(target);
+                }
+            });
+        })
+    }
+
+    pub fn mov(&mut self, target: &Ptr, source: &Ptr) {
+        if source == target {
+            return;
+        }
+
+        self.clear(target);
+
+        self.while_not_zero(source, |ctx| {
+            ctx.increment(target);
+            ctx.decrement(source);
+        })
+    }
+
+    /// Exchanges *a and *b.
+    pub fn swap(&mut self, a: &Ptr, b: &Ptr) {
+        if a == b {
+            return;
+        }
+
+        let (a_value, b_value) = (self.value(a), self.value(b));
+
+        self.with_stack_alloc(|ctx, tmp| {
+            ctx.mov(tmp, a);
+            ctx.mov(a, b);
+            ctx.mov(b, tmp);
+        });
+
+        for &(ptr, value) in &[(a, b_value), (b, a_value)] {
+            match value {
+                Some(value) => self.assume(ptr, value),
+                None => self.forget(ptr),
+            }
+        }
+    }
+
+    pub fn is_zero_destructive(&mut self, value: &Ptr) {
+        self.with_stack_alloc(|ctx, is_zero| {
+            ctx.set_bool(is_zero, true);
+
+            ctx.while_not_zero(value, |ctx| {
+                ctx.assume_bool(is_zero, true);
+                ctx.set_bool(is_zero, false);
+                ctx.set_bool(value, false);
+            });
+
+            ctx.iff_destructive(is_zero, |ctx| {
+                ctx.assume_bool(value, false);
+                ctx.set_bool(value, true);
+            })
+        })
+    }
+
+    pub fn is_zero(&mut self, source: &Ptr, target: &Ptr) {
+        self.copy(source, target);
+        self.is_zero_destructive(target);
+    }
 
     pub fn is_not_zero_destructive(&mut self, value: &Ptr) {
         self.is_zero_destructive(value);
@@ -447,6 +1357,12 @@ impl<'c> Context<'c> {
     }
 
     pub fn equals_assign(&mut self, source: &Ptr, target: &Ptr) {
+        if let (Some(source_val), Some(target_val)) = (self.value(source), self.value(target)) {
+            self.set_bool(target, source_val == target_val);
+            return;
+        }
+
+        // target - source wraps around, but is still zero exactly when both are equal.
         self.with_stack_alloc(|ctx, tmp| {
             ctx.copy(source, tmp);
             
@@ -498,11 +1414,209 @@ impl<'c> Context<'c> {
         self.greater_than_assign(a, target);
     }
 
+    /// target = *source < *target;
+    pub fn less_than_assign(&mut self, source: &Ptr, target: &Ptr) {
+        if let (Some(source_val), Some(target_val)) = (self.value(source), self.value(target)) {
+            self.set_bool(target, source_val < target_val);
+            return;
+        }
+
+        self.with_stack_alloc(|ctx, tmp| {
+            ctx.mov(tmp, target);
+            ctx.greater_than(tmp, source, target);
+        })
+    }
+
+    pub fn less_than(&mut self, a: &Ptr, b: &Ptr, target: &Ptr) {
+        self.greater_than(b, a, target);
+    }
+
+    /// target = *source <= *target;
+    pub fn less_than_or_equal_assign(&mut self, source: &Ptr, target: &Ptr) {
+        if let (Some(source_val), Some(target_val)) = (self.value(source), self.value(target)) {
+            self.set_bool(target, source_val <= target_val);
+            return;
+        }
+
+        self.greater_than_assign(source, target);
+        self.not(target);
+    }
+
+    pub fn less_than_or_equal(&mut self, a: &Ptr, b: &Ptr, target: &Ptr) {
+        if let (Some(a), Some(b)) = (self.value(a), self.value(b)) {
+            self.set_bool(target, a <= b);
+            return;
+        }
+        self.greater_than(a, b, target);
+        self.not(target);
+    }
+
+    /// target = *source >= *target;
+    pub fn greater_than_or_equal_assign(&mut self, source: &Ptr, target: &Ptr) {
+        if let (Some(source_val), Some(target_val)) = (self.value(source), self.value(target)) {
+            self.set_bool(target, source_val >= target_val);
+            return;
+        }
+
+        self.less_than_assign(source, target);
+        self.not(target);
+    }
+
+    pub fn greater_than_or_equal(&mut self, a: &Ptr, b: &Ptr, target: &Ptr) {
+        if let (Some(a), Some(b)) = (self.value(a), self.value(b)) {
+            self.set_bool(target, a >= b);
+            return;
+        }
+        self.less_than(a, b, target);
+        self.not(target);
+    }
+
+    /// target = the smaller of *a and *b;
+    pub fn min(&mut self, a: &Ptr, b: &Ptr, target: &Ptr) {
+        assert_ne!(a, target);
+        assert_ne!(b, target);
+
+        if let (Some(a), Some(b)) = (self.value(a), self.value(b)) {
+            self.set(target, a.min(b));
+            return;
+        }
+
+        self.with_stack_alloc(|ctx, a_is_greater| {
+            ctx.greater_than(a, b, a_is_greater);
+            ctx.if_else(a_is_greater, |ctx| ctx.copy(b, target), |ctx| ctx.copy(a, target));
+        })
+    }
+
+    /// target = the larger of *a and *b;
+    pub fn max(&mut self, a: &Ptr, b: &Ptr, target: &Ptr) {
+        assert_ne!(a, target);
+        assert_ne!(b, target);
+
+        if let (Some(a), Some(b)) = (self.value(a), self.value(b)) {
+            self.set(target, a.max(b));
+            return;
+        }
+
+        self.with_stack_alloc(|ctx, a_is_greater| {
+            ctx.greater_than(a, b, a_is_greater);
+            ctx.if_else(a_is_greater, |ctx| ctx.copy(a, target), |ctx| ctx.copy(b, target));
+        })
+    }
+
+    /// *ptr = min(max(*ptr, *lo), *hi);
+    /// If lo > hi, *ptr ends up as *hi.
+    pub fn clamp(&mut self, ptr: &Ptr, lo: &Ptr, hi: &Ptr) {
+        assert_ne!(ptr, lo);
+        assert_ne!(ptr, hi);
+
+        if let (Some(value), Some(lo), Some(hi)) = (self.value(ptr), self.value(lo), self.value(hi)) {
+            self.set(ptr, value.max(lo).min(hi));
+            return;
+        }
+
+        self.with_stack_alloc(|ctx, at_least_lo| {
+            ctx.max(ptr, lo, at_least_lo);
+            ctx.min(at_least_lo, hi, ptr);
+        })
+    }
+
+    /// Drives a state machine over the program's input: reads a byte into *input and
+    /// takes the matching transition with `fsm_step`, until EOF.
+    /// Assumes the interpreter reads EOF as 0; a NUL byte in the input also stops the machine.
+    pub fn run_fsm(&mut self, state: &Ptr, input: &Ptr, transitions: &[Transition]) {
+        self.read(input);
+        self.while_not_zero(input, |ctx| {
+            ctx.fsm_step(state, input, transitions);
+            ctx.read(input);
+        });
+    }
+
+    /// Takes the transition matching *state and *input, if any:
+    /// sets *state to its next state and runs its action.
+    /// Every transition is matched against the state from before the step,
+    /// so at most one of them fires.
+    pub fn fsm_step(&mut self, state: &Ptr, input: &Ptr, transitions: &[Transition]) {
+        self.with_stack_alloc4(|ctx, current, expected, matches, input_matches| {
+            ctx.copy(state, current);
+
+            for transition in transitions {
+                ctx.set(expected, transition.state);
+                ctx.equals(current, expected, matches);
+                ctx.set(expected, transition.input);
+                ctx.equals(input, expected, input_matches);
+                ctx.and_assign(input_matches, matches);
+
+                ctx.iff_destructive(matches, |ctx| {
+                    ctx.set(state, transition.next_state);
+
+                    if let Some(action) = transition.action {
+                        action(ctx);
+                    }
+                });
+            }
+        })
+    }
+
+    /// target = |*a - *b|;
+    pub fn abs_diff(&mut self, a: &Ptr, b: &Ptr, target: &Ptr) {
+        assert_ne!(a, target);
+        assert_ne!(b, target);
+
+        if let (Some(a), Some(b)) = (self.value(a), self.value(b)) {
+            self.set(target, a.max(b) - a.min(b));
+            return;
+        }
+
+        self.with_stack_alloc2(|ctx, a_is_greater, tmp| {
+            let larger_minus_smaller = |ctx: &mut Context, larger: &Ptr, smaller: &Ptr| {
+                ctx.copy(larger, target);
+                ctx.copy(smaller, tmp);
+                ctx.sub(target, tmp);
+            };
+
+            ctx.greater_than(a, b, a_is_greater);
+            ctx.if_else(
+                a_is_greater,
+                |ctx| larger_minus_smaller(ctx, a, b),
+                |ctx| larger_minus_smaller(ctx, b, a),
+            );
+        })
+    }
+
+    /// bucket = index of the first threshold that *value is less than,
+    /// or thresholds.len() if there is none.
+    /// The thresholds must be in ascending order.
+    pub fn classify(&mut self, value: &Ptr, thresholds: &[u8], bucket: &Ptr) {
+        assert_ne!(value, bucket);
+        debug_assert!(
+            thresholds.windows(2).all(|pair| pair[0] <= pair[1]),
+            "thresholds must be ascending",
+        );
+
+        // With ascending thresholds the bucket index equals the
+        // number of thresholds that *value is not less than.
+        self.clear(bucket);
+
+        for &threshold in thresholds {
+            self.with_stack_alloc2(|ctx, threshold_ptr, is_below| {
+                ctx.set(threshold_ptr, threshold);
+                ctx.greater_than(threshold_ptr, value, is_below);
+                ctx.not(is_below);
+                ctx.add(bucket, is_below);
+            })
+        }
+    }
+
     pub fn not_equals_assign(&mut self, source: &Ptr, target: &Ptr) {
         self.equals_assign(source, target);
         self.not(target);
     }
 
+    pub fn not_equals(&mut self, a: &Ptr, b: &Ptr, target: &Ptr) {
+        self.copy(b, target);
+        self.not_equals_assign(a, target);
+    }
+
     pub fn copy(&mut self, source: &Ptr, target: &Ptr) {
         if source == target {
             return;
@@ -591,234 +1705,1787 @@ impl<'c> Context<'c> {
         self.nor_assign(a, target);
     }
 
+    /// target = source != target; for boolean cells only, see `bit_xor` for bytes.
     pub fn xor_assign(&mut self, source: &Ptr, target: &Ptr) {
-        self.equals_assign(source, target);
+        self.debug_assert_bool(source);
+        self.debug_assert_bool(target);
+        self.not_equals_assign(source, target);
     }
 
+    /// target = a != b; for boolean cells only, see `bit_xor` for bytes.
     pub fn xor(&mut self, a: &Ptr, b: &Ptr, target: &Ptr) {
         assert_ne!(a, target);
         assert_ne!(b, target);
+        self.debug_assert_bool(a);
+        self.debug_assert_bool(b);
         self.copy(b, target);
         self.xor_assign(a, target);
     }
 
-    pub fn emit(&mut self, code: &str) {
-        self.code.push_str(code);
+    pub fn bool_xor(&mut self, a: &Ptr, b: &Ptr, target: &Ptr) {
+        self.xor(a, b, target);
     }
 
-    pub fn addr(&self) -> isize {
-        self.addr
-    }
-}
+    /// target = *a ^ *b; bitwise.
+    pub fn bit_xor(&mut self, a: &Ptr, b: &Ptr, target: &Ptr) {
+        assert_ne!(a, target);
+        assert_ne!(b, target);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use minibf::VM;
+        if let (Some(a), Some(b)) = (self.value(a), self.value(b)) {
+            self.set(target, a ^ b);
+            return;
+        }
 
-    #[test]
-    fn seek() {
-        let code = gen(|ctx| {
-            ctx.seek(&Ptr::new(3));
-            ctx.emit("a");
-            ctx.seek(&Ptr::new(1));
-            ctx.emit("b");
-            ctx.seek(&Ptr::new(5));
-        });
+        self.with_stack_alloc5(|ctx, rest_a, rest_b, bit_a, bit_b, differ| {
+            ctx.copy(a, rest_a);
+            ctx.copy(b, rest_b);
+            ctx.clear(target);
 
-        assert_eq!(code, ">>>a<<b>>>>");
+            for bit in 0..8 {
+                ctx.halve(rest_a, bit_a);
+                ctx.halve(rest_b, bit_b);
+                ctx.not_equals(bit_a, bit_b, differ);
+                ctx.iff_destructive(differ, |ctx| {
+                    ctx.increment_by(target, 1 << bit);
+                });
+            }
+        })
     }
 
-    #[test]
-    fn while_not_zero() {
-        let code = gen(|ctx| {
-            let a = &ctx.stack_alloc();
-            let i = &ctx.stack_alloc();
+    /// out = number of set bits in *value;
+    pub fn popcount(&mut self, value: &Ptr, out: &Ptr) {
+        assert_ne!(value, out);
 
-            ctx.set(a, 2);
-            ctx.set(i, 3);
-            ctx.while_not_zero(i, |ctx| {
-                ctx.increment(a);
-            });
-        });
+        if let Some(value) = self.value(value) {
+            self.set(out, value.count_ones() as u8);
+            return;
+        }
 
-        assert_eq!(code, "[-]++>[-]+++[<+>]");
+        self.with_stack_alloc2(|ctx, rest, bit| {
+            ctx.copy(value, rest);
+            ctx.clear(out);
+
+            for _ in 0..8 {
+                ctx.halve(rest, bit);
+                ctx.add(out, bit);
+            }
+        })
     }
 
-    #[test]
-    fn repeat_reverse_destructive() {
-        let code = gen(|ctx| {
+    /// target = element *index of the array at base;
+    /// The array must come from `alloc_indexed_array` and *index must be within it.
+    pub fn array_get(&mut self, base: &Ptr, index: &Ptr, target: &Ptr) {
+        let first_marker = base.offset(INDEXED_STRIDE);
+        let first_carry = base.offset(INDEXED_STRIDE + 1);
+
+        self.copy(index, &first_marker);
+        // The walk always ends at the base, whatever the index was.
+        self.walk(&first_marker, concat!(
+            // Walk right, leaving a marker in every element passed, until the index is used up.
+            "[-[->>>+<<<]+>>>]",
+            // Copy the data into the carry, using the marker as temporary.
+            ">>[-<+<+>>]<<[->>+<<]",
+            // Walk back over the markers, taking the carry along and clearing the markers.
+            "<<<[->>>>[-<<<+>>>]<<<<<<<]",
+        ), base);
+        self.assume(&first_marker, 0);
+        self.mov(target, &first_carry);
+    }
+
+    /// element *index of the array at base = *value;
+    /// The array must come from `alloc_indexed_array` and *index must be within it.
+    pub fn array_set(&mut self, base: &Ptr, index: &Ptr, value: &Ptr) {
+        let first_marker = base.offset(INDEXED_STRIDE);
+        let first_carry = base.offset(INDEXED_STRIDE + 1);
+
+        self.copy(index, &first_marker);
+        self.copy(value, &first_carry);
+        // The walk always ends at the base, whatever the index was.
+        self.walk(&first_marker, concat!(
+            // Walk right with the carry, leaving a marker in every element passed.
+            "[-[->>>+<<<]>[->>>+<<<]<+>>>]",
+            // Replace the data with the carry.
+            ">>[-]<[->+<]<",
+            // Walk back, clearing the markers.
+            "<<<[-<<<]",
+        ), base);
+    }
+
+    /// out = wrapping sum of the `count` cells starting at `start`;
+    pub fn checksum(&mut self, start: &Ptr, count: usize, out: &Ptr) {
+        let cells = (0..count)
+            .map(|i| start.offset(i as isize))
+            .collect::<Vec<_>>();
+        assert!(!cells.contains(out));
+
+        self.clear(out);
 
-            let a = &ctx.stack_alloc();
-            let i = &ctx.stack_alloc();
+        self.with_stack_alloc(|ctx, tmp| {
+            for cell in &cells {
+                ctx.copy(cell, tmp);
+                ctx.add(out, tmp);
+            }
+        })
+    }
+
+    /// Sorts the `count` cells starting at `start` in ascending order.
+    /// The emitted comparisons don't depend on the values, only on `count`, which must be at most 8.
+    pub fn sort_network(&mut self, start: &Ptr, count: usize) {
+        assert!(count < SORTING_NETWORKS.len(), "sorting networks are only available for up to 8 cells");
+
+        let cell = |i: usize| start.offset(i as isize);
+
+        for &(a, b) in SORTING_NETWORKS[count] {
+            self.compare_swap(&cell(a), &cell(b));
+        }
+    }
+
+    /// Swaps a and b if *a > *b.
+    fn compare_swap(&mut self, a: &Ptr, b: &Ptr) {
+        self.with_stack_alloc(|ctx, greater| {
+            ctx.greater_than(a, b, greater);
+            ctx.iff_destructive(greater, |ctx| ctx.swap(a, b));
+        })
+    }
+
+    /// out = floor(sqrt(*value));
+    pub fn isqrt(&mut self, value: &Ptr, out: &Ptr) {
+        assert_ne!(value, out);
+
+        if let Some(value) = self.value(value) {
+            let root = (0..16).take_while(|root| root * root <= value as u16).last().unwrap_or(0);
+            self.set(out, root as u8);
+            return;
+        }
+
+        // Sums of the first n odd numbers are exactly the squares,
+        // so count how many fit into value.
+        self.with_stack_alloc4(|ctx, rest, odd, too_big, fits| {
+            ctx.copy(value, rest);
+            ctx.set(odd, 1);
+            ctx.clear(out);
+
+            let check = |ctx: &mut Context| {
+                ctx.greater_than(odd, rest, too_big);
+                ctx.is_zero(too_big, fits);
+            };
+
+            check(ctx);
+            ctx.while_true(fits, |ctx| {
+                ctx.with_stack_alloc(|ctx, tmp| {
+                    ctx.copy(odd, tmp);
+                    ctx.sub(rest, tmp);
+                });
+                ctx.increment_by(odd, 2);
+                ctx.increment(out);
+                check(ctx);
+            });
+        })
+    }
+
+    /// target = *value is odd;
+    pub fn is_odd(&mut self, value: &Ptr, target: &Ptr) {
+        assert_ne!(value, target);
+
+        if let Some(value) = self.value(value) {
+            self.set_bool(target, value % 2 == 1);
+            return;
+        }
+
+        self.with_stack_alloc(|ctx, rest| {
+            ctx.copy(value, rest);
+            ctx.halve(rest, target);
+        })
+    }
+
+    /// target += amount if *value is odd;
+    pub fn add_if_odd(&mut self, value: &Ptr, target: &Ptr, amount: u8) {
+        assert_ne!(value, target);
+
+        self.with_stack_alloc(|ctx, odd| {
+            ctx.is_odd(value, odd);
+            ctx.iff_destructive(odd, |ctx| ctx.increment_by(target, amount));
+        })
+    }
+
+    /// *value = *value / 2; remainder = *value % 2;
+    fn halve(&mut self, value: &Ptr, remainder: &Ptr) {
+        self.with_stack_alloc(|ctx, quotient| {
+            ctx.clear(quotient);
+            ctx.clear(remainder);
+
+            ctx.repeat_reverse_destructive(value, |ctx, _| {
+                ctx.not(remainder);
+                ctx.if_not(remainder, |ctx| ctx.increment(quotient));
+            });
+
+            ctx.mov(value, quotient);
+        })
+    }
+
+    fn debug_assert_bool(&self, ptr: &Ptr) {
+        if let Some(value) = self.value(ptr) {
+            debug_assert!(value <= 1, "expected a boolean cell, found known value {}", value);
+        }
+    }
+
+    pub fn emit(&mut self, code: &str) {
+        for op in code.chars() {
+            let counter = match op {
+                '+' => &mut self.stats.increments,
+                '-' => &mut self.stats.decrements,
+                '<' => &mut self.stats.moves_left,
+                '>' => &mut self.stats.moves_right,
+                '[' => &mut self.stats.loop_starts,
+                ']' => &mut self.stats.loop_ends,
+                _ => continue,
+            };
+
+            *counter += 1;
+        }
+
+        self.emitted_len += code.len();
+        self.code.push_str(code);
+    }
+
+    /// Length of the code emitted so far, also for contexts from `with_writer`.
+    pub fn emitted_len(&self) -> usize {
+        self.emitted_len
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// The code emitted so far.
+    /// Panics for a context from `with_writer`, which doesn't keep its code.
+    pub fn code(&self) -> &str {
+        self.code.get()
+    }
+
+    /// Consumes the context and returns the code it emitted.
+    /// A context from `new` or `with_addr` returns a copy of the borrowed string.
+    /// Panics for a context from `with_writer`, which doesn't keep its code.
+    pub fn finish(self) -> String {
+        match self.code {
+            Sink::Borrowed(code) => code.clone(),
+            Sink::Owned(code) => code,
+            Sink::Writer(_) => panic!("{}", WRITER_KEEPS_NO_CODE),
+        }
+    }
+
+    pub fn addr(&self) -> isize {
+        self.addr
+    }
+
+    /// The highest address the head has been moved to.
+    /// Moves inside a `walk`, like those of `array_get`, `array_set` and `strlen`,
+    /// depend on the tape and aren't included.
+    pub fn max_addr(&self) -> isize {
+        self.max_addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use minibf::VM;
+
+    #[test]
+    fn owned() {
+        let code = {
+            let mut ctx = Context::owned();
+            ctx.with_stack_alloc(|ctx, ptr| ctx.set(ptr, 2));
+            assert_eq!(ctx.code(), "[-]++");
+            ctx.finish()
+        };
+
+        assert_eq!(code, "[-]++");
+    }
+
+    #[test]
+    fn with_writer() {
+        struct Counter(usize);
+
+        impl fmt::Write for Counter {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                self.0 += s.len();
+                Ok(())
+            }
+        }
+
+        let mut counter = Counter(0);
+        let mut ctx = Context::with_writer(&mut counter);
+        ctx.with_stack_alloc2(|ctx, a, b| {
+            ctx.set(a, 3);
+            ctx.set(b, 1);
+        });
+        drop(ctx);
+        assert_eq!(counter.0, "[-]+++>[-]+".len());
+
+        let mut code = String::new();
+        let mut ctx = Context::with_writer(&mut code);
+        ctx.emit("+.");
+        drop(ctx);
+        assert_eq!(code, "+.");
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't keep its code")]
+    fn with_writer_has_no_code() {
+        let mut code = String::new();
+        let mut ctx = Context::with_writer(&mut code);
+        ctx.emit("+");
+        ctx.code();
+    }
+
+    #[test]
+    fn stats() {
+        let mut ctx = Context::owned();
+        ctx.with_stack_alloc2(|ctx, _, ptr| {
+            ctx.set(ptr, 5);
+            ctx.decrement(ptr);
+            ctx.seek(&Ptr::new(0));
+        });
+
+        assert_eq!(ctx.code(), ">[-]+++++-<");
+        assert_eq!(ctx.emitted_len(), ctx.code().len());
+        assert_eq!(ctx.stats(), &Stats {
+            increments: 5,
+            decrements: 2,
+            moves_left: 1,
+            moves_right: 1,
+            loop_starts: 1,
+            loop_ends: 1,
+        });
+    }
+
+    #[test]
+    fn max_addr() {
+        gen(|ctx| {
+            assert_eq!(ctx.max_addr(), 0);
+
+            ctx.with_stack_alloc3(|ctx, a, b, c| {
+                ctx.set(c, 1);
+                ctx.set(a, 1);
+                ctx.set(b, 1);
+            });
+            assert_eq!(ctx.max_addr(), 2);
+
+            ctx.skip_cells(4);
+            ctx.seek(&Ptr::new(0));
+            assert_eq!(ctx.max_addr(), 5);
+
+            // Only the ends of a walk are known.
+            ctx.set(&Ptr::new(3), 1);
+            ctx.walk(&Ptr::new(2), "[>]<", &Ptr::new(6));
+            assert_eq!((ctx.addr(), ctx.max_addr()), (6, 6));
+            assert_eq!(ctx.value(&Ptr::new(3)), None);
+        });
+    }
+
+    #[test]
+    fn seek() {
+        let code = gen(|ctx| {
+            ctx.seek(&Ptr::new(3));
+            ctx.emit("a");
+            ctx.seek(&Ptr::new(1));
+            ctx.emit("b");
+            ctx.seek(&Ptr::new(5));
+        });
+
+        assert_eq!(code, ">>>a<<b>>>>");
+    }
+
+    #[test]
+    fn alloc_guard() {
+        let mut code = String::new();
+        let ctx = &mut Context::new(&mut code);
+        let kept = ctx.alloc();
+
+        for _ in 0..3 {
+            let a = ctx.alloc();
+            let b = ctx.alloc();
+            ctx.set(&a, 1);
+            ctx.set(&b, 2);
+            assert_eq!((a.as_isize(), b.as_isize()), (1, 2));
+        }
+
+        drop(kept);
+        assert_eq!(ctx.alloc().as_isize(), 0);
+        assert_eq!(ctx.stack_size(), 3);
+
+        ctx.with_stack_alloc2(|ctx, a, b| {
+            assert_eq!((a.as_isize(), b.as_isize()), (0, 1));
+            ctx.set(a, 0);
+            ctx.set(b, 0);
+        });
+    }
+
+    #[test]
+    fn alloc_array() {
+        let mem = run(|ctx| {
+            let gap = ctx.stack_alloc();
+            let used = ctx.stack_alloc();
+            drop(gap);
+
+            // The single free cell is too small, so the array goes after `used`.
+            let array = ctx.alloc_array(3);
+            assert_eq!(array.as_isize(), 2);
+
+            for i in 0..3 {
+                ctx.set(&array.offset(i), 10 + i as u8);
+            }
+
+            let scalar = ctx.stack_alloc();
+            assert_eq!(scalar.as_isize(), 0);
+            let after = ctx.stack_alloc();
+            assert_eq!(after.as_isize(), 5);
+
+            ctx.set(&used, 1);
+            ctx.set(&scalar, 2);
+            ctx.set(&after, 3);
+
+            drop(array);
+            assert_eq!(ctx.stack_alloc().as_isize(), 2);
+        });
+
+        assert_eq!(mem[..6], [2, 1, 10, 11, 12, 3]);
+    }
+
+    #[test]
+    fn array_get() {
+        for index in 0..3 {
+            let mem = run(|ctx| {
+                let array = ctx.alloc_indexed_array(3);
+                let index_ptr = &ctx.stack_alloc();
+                let target = &ctx.stack_alloc();
+
+                for (i, &value) in [7, 0, 42].iter().enumerate() {
+                    ctx.set(&array.element(i), value);
+                }
+
+                ctx.set(index_ptr, index as u8);
+                ctx.forget(index_ptr);
+                ctx.array_get(&array, index_ptr, target);
+            });
+
+            let data = [mem[5], mem[8], mem[11]];
+            assert_eq!(data, [7, 0, 42]);
+            assert_eq!(mem[12..14], [index as u8, data[index]], "index {}", index);
+            // Markers and carries are clean again.
+            assert_eq!([mem[3], mem[4], mem[6], mem[7], mem[9], mem[10]], [0; 6]);
+        }
+    }
+
+    #[test]
+    fn array_set() {
+        for index in 0..3 {
+            let mem = run(|ctx| {
+                let array = ctx.alloc_indexed_array(3);
+                let index_ptr = &ctx.stack_alloc();
+                let value = &ctx.stack_alloc();
+
+                for (i, &value) in [7, 0, 42].iter().enumerate() {
+                    ctx.set(&array.element(i), value);
+                }
+
+                ctx.set(index_ptr, index as u8);
+                ctx.set(value, 99);
+                ctx.forget(index_ptr);
+                ctx.array_set(&array, index_ptr, value);
+            });
+
+            let mut expected = [7, 0, 42];
+            expected[index] = 99;
+            assert_eq!([mem[5], mem[8], mem[11]], expected, "index {}", index);
+            assert_eq!(mem[12..14], [index as u8, 99]);
+            assert_eq!([mem[3], mem[4], mem[6], mem[7], mem[9], mem[10]], [0; 6]);
+        }
+    }
+
+    #[test]
+    fn offset() {
+        let code = gen(|ctx| {
+            let base = ctx.stack_alloc();
+            ctx.seek(&base.offset(3));
+            ctx.emit("a");
+            ctx.seek(&base.offset(3).offset(-2));
+            assert_eq!(base.offset(-1).as_isize(), -1);
+        });
+
+        assert_eq!(code, ">>>a<<");
+    }
+
+    #[test]
+    fn skip_cells() {
+        let code = gen(|ctx| {
+            ctx.seek(&Ptr::new(2));
+            ctx.skip_cells(3);
+            assert_eq!(ctx.addr(), 5);
+            ctx.seek(&Ptr::new(1));
+        });
+
+        assert_eq!(code, ">>>>><<<<");
+    }
+
+    #[test]
+    fn while_not_zero() {
+        let code = gen(|ctx| {
+            let a = &ctx.stack_alloc();
+            let i = &ctx.stack_alloc();
+
+            ctx.set(a, 2);
+            ctx.set(i, 3);
+            ctx.while_not_zero(i, |ctx| {
+                ctx.increment(a);
+            });
+        });
+
+        assert_eq!(code, "[-]++>[-]+++[<+>]");
+    }
+
+    #[test]
+    fn while_not_zero_preserving() {
+        let mem = run(|ctx| {
+            ctx.with_stack_alloc3(|ctx, constant, counter, sum| {
+                ctx.set(constant, 5);
+                ctx.set(counter, 3);
+                ctx.set(sum, 0);
+
+                ctx.while_not_zero_preserving(counter, std::slice::from_ref(constant), |ctx| {
+                    assert_eq!(ctx.value(constant), Some(5));
+                    ctx.increment(sum);
+                    ctx.decrement(counter);
+                });
+
+                assert_eq!(ctx.value(constant), Some(5));
+                assert_eq!(ctx.value(sum), None);
+
+                let code_len = ctx.code().len();
+                ctx.add(sum, constant);
+                assert!(!ctx.code()[code_len..].replace("[-]", "").contains('['));
+            })
+        });
+
+        assert_eq!(mem[..3], [0, 0, 8]);
+    }
+
+    #[test]
+    fn bounded_loop() {
+        let mem = run(|ctx| {
+            ctx.with_stack_alloc3(|ctx, cond, count, countdown| {
+                ctx.set(cond, 1);
+                ctx.forget(cond);
+                ctx.set(count, 0);
+                ctx.bounded_loop(cond, 5, |ctx| {
+                    ctx.increment(count);
+                });
+
+                ctx.set(countdown, 3);
+                ctx.forget(countdown);
+                ctx.bounded_loop(countdown, 10, |ctx| {
+                    ctx.decrement(countdown);
+                });
+            })
+        });
+
+        assert_eq!(mem[..3], [1, 5, 0]);
+    }
+
+    #[test]
+    fn repeat_reverse_destructive() {
+        let code = gen(|ctx| {
+
+            let a = &ctx.stack_alloc();
+            let i = &ctx.stack_alloc();
+
+            ctx.set(a, 2);
+            ctx.set(i, 3);
+
+            ctx.repeat_reverse_destructive(i, |ctx, _| {
+                ctx.increment(a);
+            });
+        });
+
+        assert_eq!(code, "[-]++>[-]+++[<+>-]");
+    }
+
+    #[test]
+    fn repeat_reverse() {
+        let code = gen(|ctx| {
+            let a = &ctx.stack_alloc();
+            let i = &ctx.stack_alloc();
+
+            ctx.set(a, 2);
+            ctx.set(i, 3);
+
+            ctx.repeat_reverse(i, |ctx, _| {
+                ctx.increment(a);
+            });
+        });
+
+        assert_eq!(code, "[-]++>[-]+++>[-]>[-]<<[>>+<<-]>>[<<+>+>-]<[<<+>>-]");
+    }
+
+    #[test]
+    fn set() {
+        let code = gen(|ctx| {
+            ctx.set(&Ptr::new(3), 13);
+        });
+
+        assert_eq!(code, ">>>[-]+++++++++++++");
+    }
+
+    #[test]
+    fn fill() {
+        let mut known = Vec::new();
+        let code = gen(|ctx| {
+            let start = ctx.stack_alloc();
+            ctx.fill(&start, 4, 9);
+            ctx.clear_range(&start.offset(4), 2);
+            known = (0..6).map(|i| ctx.value(&start.offset(i))).collect();
+        });
+
+        assert_eq!(known, [Some(9), Some(9), Some(9), Some(9), Some(0), Some(0)]);
+
+        let mut vm = VM::new();
+        vm.mem_mut()[4] = 1;
+        vm.mem_mut()[5] = 1;
+        vm.run(&code).unwrap();
+        assert_eq!(vm.mem()[..6], [9, 9, 9, 9, 0, 0]);
+    }
+
+    #[test]
+    fn known_values_wrap() {
+        gen(|ctx| {
+            ctx.with_stack_alloc(|ctx, ptr| {
+                ctx.set(ptr, 255);
+                ctx.increment(ptr);
+                assert_eq!(ctx.value(ptr), Some(0));
+
+                ctx.decrement(ptr);
+                assert_eq!(ctx.value(ptr), Some(255));
+
+                ctx.increment_by(ptr, 3);
+                assert_eq!(ctx.value(ptr), Some(2));
+
+                ctx.decrement_by(ptr, 5);
+                assert_eq!(ctx.value(ptr), Some(253));
+            })
+        });
+    }
+
+    #[test]
+    fn digit_conversion() {
+        let mem = run(|ctx| {
+            ctx.with_stack_alloc2(|ctx, num, digit| {
+                ctx.set(num, b'7');
+                ctx.forget(num);
+                ctx.digit_to_num(num);
+                ctx.copy(num, digit);
+                ctx.num_to_digit(digit);
+            })
+        });
+
+        assert_eq!(mem[..2], [7, b'7']);
+
+        gen(|ctx| {
+            ctx.with_stack_alloc(|ctx, ptr| {
+                ctx.set(ptr, b'7');
+                ctx.digit_to_num(ptr);
+                assert_eq!(ctx.value(ptr), Some(7));
+                ctx.num_to_digit(ptr);
+                assert_eq!(ctx.value(ptr), Some(b'7'));
+            })
+        });
+    }
+
+    #[test]
+    fn not() {
+        let mem = run(|ctx| {
+            ctx.with_stack_alloc2(|ctx, a, b| {
+                ctx.set_bool(a, false);
+                ctx.set_bool(b, true);
+                ctx.not(a);
+                ctx.not(b);
+            })
+        });
+
+        assert_eq!(mem[..2], [1, 0]);
+    }
+
+    #[test]
+    fn or() {
+        let mem = run(|ctx| {
+            ctx.with_stack_alloc2(|ctx, false_, true_| {
+                ctx.with_stack_alloc4(|ctx, a, b, c, d| {
+                    ctx.set_bool(false_, false);
+                    ctx.set_bool(true_, true);
+                    ctx.or(false_, false_, a);
+                    ctx.or(false_,  true_, b);
+                    ctx.or( true_, false_, c);
+                    ctx.or( true_,  true_, d);
+                })
+            })
+        });
+
+        assert_eq!(mem[..6], [0, 1, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn xor() {
+        let mem = run(|ctx| {
+            ctx.with_stack_alloc2(|ctx, false_, true_| {
+                ctx.with_stack_alloc4(|ctx, a, b, c, d| {
+                    ctx.set_bool(false_, false);
+                    ctx.set_bool(true_, true);
+                    ctx.xor(false_, false_, a);
+                    ctx.xor(false_,  true_, b);
+                    ctx.xor( true_, false_, c);
+                    ctx.xor( true_,  true_, d);
+                })
+            })
+        });
+
+        assert_eq!(mem[..6], [0, 1, 0, 1, 1, 0]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "expected a boolean cell")]
+    fn xor_non_bool() {
+        gen(|ctx| {
+            ctx.with_stack_alloc3(|ctx, a, b, target| {
+                ctx.set(a, 5);
+                ctx.set_bool(b, true);
+                ctx.xor(a, b, target);
+            })
+        });
+    }
+
+    #[test]
+    fn bit_xor() {
+        for &(a, b) in &[(0b1100, 0b1010), (255, 15), (0, 0), (200, 200)] {
+            let mem = run(|ctx| {
+                ctx.with_stack_alloc3(|ctx, a_ptr, b_ptr, target| {
+                    ctx.set(a_ptr, a);
+                    ctx.set(b_ptr, b);
+                    ctx.forget(a_ptr);
+                    ctx.forget(b_ptr);
+                    ctx.bit_xor(a_ptr, b_ptr, target);
+                })
+            });
+
+            assert_eq!(mem[..3], [a, b, a ^ b], "{} ^ {}", a, b);
+        }
+    }
+
+    #[test]
+    fn popcount() {
+        for &value in &[0, 1, 255, 0b10110, 128] {
+            let mem = run(|ctx| {
+                ctx.with_stack_alloc2(|ctx, value_ptr, out| {
+                    ctx.set(value_ptr, value);
+                    ctx.forget(value_ptr);
+                    ctx.popcount(value_ptr, out);
+                })
+            });
+
+            assert_eq!(mem[..2], [value, value.count_ones() as u8], "popcount({})", value);
+        }
+
+        let code = gen(|ctx| {
+            ctx.with_stack_alloc2(|ctx, value, out| {
+                ctx.set(value, 0b1011);
+                ctx.popcount(value, out);
+            })
+        });
+        assert!(!code.replace("[-]", "").contains('['));
+    }
+
+    #[test]
+    fn checksum() {
+        let block = [200, 100, 3, 0, 250];
+
+        let mem = run(|ctx| {
+            let cells = block.iter().map(|_| ctx.stack_alloc()).collect::<Vec<_>>();
+            let out = &ctx.stack_alloc();
+            let empty = &ctx.stack_alloc();
+
+            for (cell, &value) in cells.iter().zip(&block) {
+                ctx.set(cell, value);
+            }
+
+            ctx.forget_known_values();
+            ctx.checksum(&cells[0], cells.len(), out);
+            ctx.set(empty, 1);
+            ctx.checksum(&cells[0], 0, empty);
+        });
+
+        let sum = block.iter().fold(0u8, |sum, &value| sum.wrapping_add(value));
+        assert_eq!(mem[..5], block);
+        assert_eq!(mem[5..7], [sum, 0]);
+    }
+
+    #[test]
+    fn isqrt() {
+        for &value in &[0, 1, 2, 3, 4, 15, 16, 17, 99, 100, 224, 225, 255] {
+            let code = gen(|ctx| {
+                ctx.with_stack_alloc3(|ctx, value_ptr, folded, out| {
+                    ctx.set(value_ptr, value);
+                    ctx.isqrt(value_ptr, folded);
+                    ctx.forget(value_ptr);
+                    ctx.isqrt(value_ptr, out);
+                })
+            });
+
+            // The comparisons take a lot of steps for large values.
+            let mut vm = VM::<u8>::with_config(minibf::VMConfig { max_steps: 20_000_000, ..Default::default() });
+            vm.run(&code).unwrap();
+
+            let expected = (value as f64).sqrt() as u8;
+            assert_eq!(vm.mem()[..3], [value, expected, expected], "isqrt({})", value);
+        }
+    }
+
+    #[test]
+    fn add_if_odd() {
+        for &value in &[0, 1, 2, 7, 100, 255] {
+            let mem = run(|ctx| {
+                ctx.with_stack_alloc2(|ctx, value_ptr, target| {
+                    ctx.set(value_ptr, value);
+                    ctx.set(target, 10);
+                    ctx.forget_known_values();
+                    ctx.add_if_odd(value_ptr, target, 5);
+                })
+            });
+
+            let expected = if value % 2 == 1 { 15 } else { 10 };
+            assert_eq!(mem[..2], [value, expected], "{}", value);
+        }
+    }
+
+    #[test]
+    fn is_odd_into_known_target() {
+        for &value in &[0, 1, 2, 7] {
+            for &known in &[0, 1] {
+                let mem = run(|ctx| {
+                    ctx.with_stack_alloc2(|ctx, value_ptr, target| {
+                        ctx.set(value_ptr, value);
+                        ctx.set(target, known);
+                        ctx.is_odd(value_ptr, target);
+                    })
+                });
+
+                assert_eq!(mem[..2], [value, value % 2], "{} into {}", value, known);
+            }
+        }
+
+        // The temporary `odd` cell is reused and still known from the first call.
+        let mem = run(|ctx| {
+            ctx.with_stack_alloc2(|ctx, value_ptr, target| {
+                ctx.set(value_ptr, 4);
+                ctx.set(target, 10);
+                ctx.add_if_odd(value_ptr, target, 5);
+                ctx.set(value_ptr, 3);
+                ctx.add_if_odd(value_ptr, target, 5);
+                ctx.add_if_odd(value_ptr, target, 5);
+            })
+        });
+
+        assert_eq!(mem[..2], [3, 20]);
+    }
+
+    #[test]
+    fn clamp() {
+        for &(value, lo, hi, expected) in &[(2, 5, 10, 5), (7, 5, 10, 7), (30, 5, 10, 10), (5, 5, 10, 5), (7, 10, 5, 5)] {
+            let mem = run(|ctx| {
+                ctx.with_stack_alloc3(|ctx, ptr, lo_ptr, hi_ptr| {
+                    ctx.set(ptr, value);
+                    ctx.set(lo_ptr, lo);
+                    ctx.set(hi_ptr, hi);
+                    ctx.forget_known_values();
+                    ctx.clamp(ptr, lo_ptr, hi_ptr);
+                })
+            });
+
+            assert_eq!(mem[..3], [expected, lo, hi], "clamp({}, {}, {})", value, lo, hi);
+        }
+
+        let code = gen(|ctx| {
+            ctx.with_stack_alloc3(|ctx, ptr, lo, hi| {
+                ctx.set(ptr, 30);
+                ctx.set(lo, 5);
+                ctx.set(hi, 10);
+                ctx.clamp(ptr, lo, hi);
+            })
+        });
+        assert!(!code.replace("[-]", "").contains('['));
+    }
+
+    #[test]
+    fn sort_network() {
+        let blocks: &[&[u8]] = &[
+            &[4, 3, 2, 1],
+            &[7, 0, 7, 1],
+            &[1, 2, 3, 4],
+            &[9, 2, 30, 5, 2],
+            &[0, 0, 1, 0, 0],
+            &[12, 11, 10, 9, 8],
+        ];
+
+        for &block in blocks {
+            let mem = run(|ctx| {
+                let cells = block.iter().map(|_| ctx.stack_alloc()).collect::<Vec<_>>();
+
+                for (cell, &value) in cells.iter().zip(block) {
+                    ctx.set(cell, value);
+                }
+
+                ctx.forget_known_values();
+                ctx.sort_network(&cells[0], cells.len());
+            });
+
+            let mut expected = block.to_vec();
+            expected.sort();
+            assert_eq!(mem[..block.len()], expected[..], "{:?}", block);
+        }
+    }
+
+    #[test]
+    fn and() {
+        let mem = run(|ctx| {
+            ctx.with_stack_alloc2(|ctx, false_, true_| {
+                ctx.with_stack_alloc4(|ctx, a, b, c, d| {
+                    ctx.set_bool(false_, false);
+                    ctx.set_bool(true_, true);
+                    ctx.and(false_, false_, a);
+                    ctx.and(false_,  true_, b);
+                    ctx.and( true_, false_, c);
+                    ctx.and( true_,  true_, d);
+                })
+            })
+        });
+
+        assert_eq!(mem[..6], [0, 1, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn add() {
+        let mem = run(|ctx| {
+            ctx.with_stack_alloc4(|ctx, a, b, c, d| {
+                ctx.set(a, 6);
+                ctx.set(b, 7);
+                ctx.set(c, 8);
+                ctx.set(d, 9);
+                ctx.add(a, b);
+                ctx.add(d, c);
+            })
+        });
+
+        assert_eq!(mem[..4], [13, 0, 0, 17]);
+    }
+
+    #[test]
+    fn add_known_source() {
+        let code = gen(|ctx| {
+            ctx.with_stack_alloc2(|ctx, a, b| {
+                ctx.set(a, 2);
+                ctx.set(b, 5);
+                ctx.add(a, b);
+
+                assert_eq!(ctx.value(a), Some(7));
+                assert_eq!(ctx.value(b), Some(0));
+            })
+        });
+
+        assert_eq!(code, "[-]++>[-]+++++<+++++>[-]");
+    }
+
+    #[test]
+    fn sub_known_source() {
+        let code = gen(|ctx| {
+            ctx.with_stack_alloc2(|ctx, a, b| {
+                ctx.set(a, 7);
+                ctx.set(b, 5);
+                ctx.sub(a, b);
+
+                assert_eq!(ctx.value(a), Some(2));
+            })
+        });
+
+        assert_eq!(code, "[-]+++++++>[-]+++++<----->[-]");
+    }
+
+    #[test]
+    fn known_values_after_loop() {
+        gen(|ctx| {
+            ctx.with_stack_alloc2(|ctx, a, i| {
+                ctx.set(a, 2);
+                ctx.read(i);
+                ctx.while_not_zero(i, |ctx| {
+                    ctx.set(a, 5);
+                    ctx.clear(i);
+                });
+
+                assert_eq!(ctx.value(a), None);
+                assert_eq!(ctx.value(i), Some(0));
+            })
+        });
+    }
+
+    #[test]
+    fn saturating() {
+        let cases: &[(u8, u8)] = &[(200, 100), (5, 10), (100, 55), (10, 5), (255, 0), (0, 0)];
+
+        for &(a, b) in cases {
+            let mem = run(|ctx| {
+                ctx.with_stack_alloc3(|ctx, sum, difference, source| {
+                    ctx.set(sum, a);
+                    ctx.set(difference, a);
+                    ctx.set(source, b);
+                    ctx.forget_known_values();
+                    ctx.saturating_add(sum, source);
+                    ctx.saturating_sub(difference, source);
+                })
+            });
+
+            assert_eq!(mem[..3], [a.saturating_add(b), a.saturating_sub(b), b], "{} {}", a, b);
+        }
+    }
+
+    #[test]
+    fn negate() {
+        for &(value, negated) in &[(1, 255), (0, 0), (200, 56)] {
+            let mem = run(|ctx| {
+                ctx.with_stack_alloc(|ctx, ptr| {
+                    ctx.set(ptr, value);
+                    ctx.forget(ptr);
+                    ctx.negate(ptr);
+                })
+            });
+
+            assert_eq!(mem[0], negated, "-{}", value);
+
+            gen(|ctx| {
+                ctx.with_stack_alloc(|ctx, ptr| {
+                    ctx.set(ptr, value);
+                    ctx.negate(ptr);
+                    assert_eq!(ctx.value(ptr), Some(negated));
+                })
+            });
+        }
+    }
+
+    #[test]
+    fn copy_to_all() {
+        let fan_out = |ctx: &mut Context, single_pass: bool| {
+            let source = &ctx.stack_alloc();
+            let targets = (0..4).map(|_| ctx.stack_alloc()).collect::<Vec<_>>();
+
+            ctx.set(source, 7);
+            ctx.forget(source);
+
+            if single_pass {
+                ctx.copy_to_all(source, &targets.iter().collect::<Vec<_>>());
+            } else {
+                for target in &targets {
+                    ctx.copy(source, target);
+                }
+            }
+        };
+
+        let single = gen(|ctx| fan_out(ctx, true));
+        let naive = gen(|ctx| fan_out(ctx, false));
+        assert!(single.len() < naive.len(), "{} >= {}", single.len(), naive.len());
+
+        let mem = run(|ctx| fan_out(ctx, true));
+        assert_eq!(mem[..5], [7; 5]);
+    }
+
+    #[test]
+    fn ring_buffer() {
+        let mem = run(|ctx| {
+            let ring = ctx.alloc_ring_buffer(3);
+            let value = &ctx.stack_alloc();
+            let popped = (0..4).map(|_| ctx.stack_alloc()).collect::<Vec<_>>();
+            ctx.forget(&ring.head);
+            ctx.forget(&ring.tail);
+
+            for n in 1..=3 {
+                ctx.set(value, n);
+                ctx.ring_push(&ring, value);
+            }
+
+            ctx.ring_pop(&ring, &popped[0]);
+            ctx.set(value, 4);
+            ctx.ring_push(&ring, value);
+
+            for target in &popped[1..] {
+                ctx.ring_pop(&ring, target);
+            }
+        });
+
+        // Three slots, head, tail and the pushed value come before the popped values.
+        assert_eq!(mem[6..10], [1, 2, 3, 4]);
+        // Both indices wrapped around once.
+        assert_eq!(mem[3..5], [1, 1]);
+    }
+
+    #[test]
+    fn mul() {
+        let mem = run(|ctx| {
+            ctx.with_stack_alloc4(|ctx, a, b, c, d| {
+                ctx.set(a, 6);
+                ctx.set(b, 7);
+                ctx.set(c, 8);
+                ctx.set(d, 9);
+                ctx.mul(a, b);
+                ctx.mul(d, c);
+            })
+        });
+
+        assert_eq!(mem[..4], [42, 7, 8, 72]);
+    }
+
+
+    #[test]
+    fn mul_binary() {
+        // `mul` gets too slow for the test VM with two large operands.
+        for &a in &[0, 1, 3, 16, 100] {
+            for &b in &[0, 2, 7, 60] {
+                let mem = run(|ctx| {
+                    ctx.with_stack_alloc4(|ctx, a_ptr, b_ptr, binary, naive| {
+                        ctx.set(a_ptr, a);
+                        ctx.set(b_ptr, b);
+                        ctx.forget_known_values();
+                        ctx.mul_binary(a_ptr, b_ptr, binary);
+                        ctx.copy(a_ptr, naive);
+                        ctx.mul(naive, b_ptr);
+                    })
+                });
+
+                assert_eq!(mem[..4], [a, b, a.wrapping_mul(b), a.wrapping_mul(b)], "{} * {}", a, b);
+            }
+        }
+
+        let mem = run(|ctx| {
+            ctx.with_stack_alloc3(|ctx, a, b, target| {
+                ctx.set(a, 255);
+                ctx.set(b, 200);
+                ctx.forget_known_values();
+                ctx.mul_binary(a, b, target);
+            })
+        });
+        assert_eq!(mem[2], 255u8.wrapping_mul(200));
+    }
+
+    #[test]
+    fn mul_const() {
+        let code = gen(|ctx| {
+            ctx.with_stack_alloc(|ctx, ptr| ctx.mul_const(ptr, 4));
+        });
 
-            ctx.set(a, 2);
-            ctx.set(i, 3);
+        assert_eq!(code, ">[-]<[>+<-]>[<++++>-]");
 
-            ctx.repeat_reverse_destructive(i, |ctx, _| {
-                ctx.increment(a);
+        for &(value, factor) in &[(7, 10), (3, 0), (9, 1), (30, 10)] {
+            let mem = run(|ctx| {
+                ctx.with_stack_alloc(|ctx, ptr| {
+                    ctx.set(ptr, value);
+                    ctx.forget(ptr);
+                    ctx.mul_const(ptr, factor);
+                })
             });
+
+            assert_eq!(mem[..2], [value.wrapping_mul(factor), 0], "{} * {}", value, factor);
+        }
+
+        let with_const = gen(|ctx| {
+            ctx.with_stack_alloc(|ctx, ptr| ctx.mul_const(ptr, 10));
+        });
+        let with_mul = gen(|ctx| {
+            ctx.with_stack_alloc2(|ctx, ptr, factor| {
+                ctx.set(factor, 10);
+                ctx.mul(ptr, factor);
+            })
         });
 
-        assert_eq!(code, "[-]++>[-]+++[<+>-]");
+        assert!(with_const.len() < with_mul.len());
     }
 
     #[test]
-    fn repeat_reverse() {
-        let code = gen(|ctx| {
-            let a = &ctx.stack_alloc();
-            let i = &ctx.stack_alloc();
+    fn mul_assign() {
+        for &(a, b) in &[(6, 7), (0, 5), (5, 0)] {
+            let mem = run(|ctx| {
+                ctx.with_stack_alloc2(|ctx, target, source| {
+                    ctx.set(target, a);
+                    ctx.set(source, b);
+                    ctx.forget(target);
+                    ctx.forget(source);
+                    ctx.mul_assign(target, source);
+                })
+            });
 
-            ctx.set(a, 2);
-            ctx.set(i, 3);
+            assert_eq!(mem[..2], [a * b, 0], "{} * {}", a, b);
+        }
+    }
 
-            ctx.repeat_reverse(i, |ctx, _| {
-                ctx.increment(a);
+    #[test]
+    fn sub() {
+        let mem = run(|ctx| {
+            ctx.with_stack_alloc4(|ctx, a, b, c, d| {
+                ctx.set(a, 9);
+                ctx.set(b, 8);
+                ctx.set(c, 6);
+                ctx.set(d, 7);
+                ctx.sub(a, b);
+                ctx.sub(d, c);
+            })
+        });
+
+        assert_eq!(mem[..4], [1, 0, 0, 1]);
+    }
+
+    #[test]
+    fn greater_than() {
+        let mem = run(|ctx| {
+            ctx.with_stack_alloc5(|ctx, a, b, r1, r2, r3| {
+                ctx.set(a, 6);
+                ctx.set(b, 10);
+                ctx.greater_than(a, b, r1);
+                ctx.greater_than(b, a, r2);
+                ctx.greater_than(a, a, r3);
+            })
+        });
+
+        assert_eq!(mem[..5], [6, 10, 0, 1, 0]);
+    }
+
+    #[test]
+    fn div_mod() {
+        for &(dividend, divisor, quotient, remainder) in &[(17, 5, 3, 2), (10, 2, 5, 0), (3, 7, 0, 3), (9, 0, 0, 0), (0, 4, 0, 0)] {
+            for &known_divisor in &[false, true] {
+                let mem = run(|ctx| {
+                    ctx.with_stack_alloc5(|ctx, before, a, b, q, r| {
+                        ctx.with_stack_alloc(|ctx, after| {
+                            ctx.set(before, 42);
+                            ctx.set(after, 24);
+                            ctx.set(a, dividend);
+                            ctx.set(b, divisor);
+                            ctx.forget(a);
+                            if !known_divisor {
+                                ctx.forget(b);
+                            }
+                            ctx.div_mod(a, b, q, r);
+                        })
+                    })
+                });
+
+                assert_eq!(mem[..6], [42, dividend, divisor, quotient, remainder, 24], "{} / {} (known divisor: {})", dividend, divisor, known_divisor);
+            }
+        }
+    }
+
+    #[test]
+    fn modulo() {
+        for &(a, b, remainder) in &[(13, 5, 3), (8, 8, 0), (3, 7, 3), (6, 0, 0)] {
+            let mem = run(|ctx| {
+                ctx.with_stack_alloc3(|ctx, a_ptr, b_ptr, target| {
+                    ctx.set(a_ptr, a);
+                    ctx.set(b_ptr, b);
+                    ctx.forget(a_ptr);
+                    ctx.forget(b_ptr);
+                    ctx.modulo(a_ptr, b_ptr, target);
+                })
             });
+
+            assert_eq!(mem[..3], [a, b, remainder], "{} % {}", a, b);
+        }
+    }
+
+    #[test]
+    fn div_mod_known() {
+        let code = gen(|ctx| {
+            ctx.with_stack_alloc4(|ctx, a, b, q, r| {
+                ctx.set(a, 17);
+                ctx.set(b, 5);
+                ctx.div_mod(a, b, q, r);
+
+                assert_eq!(ctx.value(q), Some(3));
+                assert_eq!(ctx.value(r), Some(2));
+            })
         });
 
-        assert_eq!(code, "[-]++>[-]+++>[-]>[-]<<[>>+<<-]>>[<<+>+>-]<[<<+>>-]");
+        assert!(!code.replace("[-]", "").contains('['));
     }
 
     #[test]
-    fn set() {
+    fn strlen() {
+        for &text in &[&b"hello"[..], b"x", b""] {
+            let mem = run(|ctx| {
+                ctx.with_stack_alloc(|ctx, len| {
+                    for (i, &byte) in text.iter().enumerate() {
+                        ctx.set(&Ptr::new(10 + i as isize), byte);
+                    }
+                    ctx.set(&Ptr::new(11 + text.len() as isize), 99);
+                    ctx.forget_known_values();
+                    ctx.strlen(&Ptr::new(10), len);
+                })
+            });
+
+            let end = 10 + text.len();
+            assert_eq!(mem[0] as usize, text.len());
+            assert_eq!(&mem[10..end], text);
+            assert_eq!(mem[end..end + 2], [0, 99]);
+        }
+    }
+
+    #[test]
+    fn equals() {
+        let values = [0, 1, 2, 3, 5, 128, 254, 255];
+
+        for &a in &values {
+            for &b in &values {
+                for &known in &[false, true] {
+                    let mem = run(|ctx| {
+                        ctx.with_stack_alloc3(|ctx, a_ptr, b_ptr, target| {
+                            ctx.set(a_ptr, a);
+                            ctx.set(b_ptr, b);
+                            if !known {
+                                ctx.forget(a_ptr);
+                                ctx.forget(b_ptr);
+                            }
+                            ctx.equals(a_ptr, b_ptr, target);
+                        })
+                    });
+
+                    assert_eq!(mem[..3], [a, b, (a == b) as u8], "{} == {} (known: {})", a, b, known);
+                }
+
+                let mem = run(|ctx| {
+                    ctx.with_stack_alloc2(|ctx, source, target| {
+                        ctx.set(source, a);
+                        ctx.set(target, b);
+                        ctx.equals_assign(source, target);
+                        assert_eq!(ctx.value(target), Some((a == b) as u8), "known {} == {}", a, b);
+                    })
+                });
+
+                assert_eq!(mem[..2], [a, (a == b) as u8], "{} == {} (assign)", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn orderings() {
+        type Op = fn(&mut Context, &Ptr, &Ptr, &Ptr);
+        type AssignOp = fn(&mut Context, &Ptr, &Ptr);
+        type Expected = fn(&u8, &u8) -> bool;
+        let ops: [(&str, Op, AssignOp, Expected); 3] = [
+            ("<", |ctx, a, b, t| ctx.less_than(a, b, t), |ctx, s, t| ctx.less_than_assign(s, t), u8::lt),
+            ("<=", |ctx, a, b, t| ctx.less_than_or_equal(a, b, t), |ctx, s, t| ctx.less_than_or_equal_assign(s, t), u8::le),
+            (">=", |ctx, a, b, t| ctx.greater_than_or_equal(a, b, t), |ctx, s, t| ctx.greater_than_or_equal_assign(s, t), u8::ge),
+        ];
+        let values = [0, 1, 2, 5, 9];
+
+        for &(name, op, assign_op, expected) in &ops {
+            for &a in &values {
+                for &b in &values {
+                    let expected = expected(&a, &b) as u8;
+
+                    let mem = run(|ctx| {
+                        ctx.with_stack_alloc3(|ctx, a_ptr, b_ptr, target| {
+                            ctx.set(a_ptr, a);
+                            ctx.set(b_ptr, b);
+                            ctx.forget(a_ptr);
+                            ctx.forget(b_ptr);
+                            op(ctx, a_ptr, b_ptr, target);
+                        })
+                    });
+                    assert_eq!(mem[..3], [a, b, expected], "{} {} {}", a, name, b);
+
+                    let mem = run(|ctx| {
+                        ctx.with_stack_alloc2(|ctx, a_ptr, b_ptr| {
+                            ctx.set(a_ptr, a);
+                            ctx.set(b_ptr, b);
+                            ctx.forget(a_ptr);
+                            ctx.forget(b_ptr);
+                            assign_op(ctx, a_ptr, b_ptr);
+                        })
+                    });
+                    assert_eq!(mem[..2], [a, expected], "{} {}= {}", a, name, b);
+
+                    let mem = run(|ctx| {
+                        ctx.with_stack_alloc3(|ctx, a_ptr, b_ptr, target| {
+                            ctx.set(a_ptr, a);
+                            ctx.set(b_ptr, b);
+                            op(ctx, a_ptr, b_ptr, target);
+                            assert_eq!(ctx.value(target), Some(expected), "known {} {} {}", a, name, b);
+                        })
+                    });
+                    assert_eq!(mem[..3], [a, b, expected], "known {} {} {}", a, name, b);
+
+                    let mem = run(|ctx| {
+                        ctx.with_stack_alloc2(|ctx, a_ptr, b_ptr| {
+                            ctx.set(a_ptr, a);
+                            ctx.set(b_ptr, b);
+                            assign_op(ctx, a_ptr, b_ptr);
+                            assert_eq!(ctx.value(b_ptr), Some(expected), "known {} {}= {}", a, name, b);
+                        })
+                    });
+                    assert_eq!(mem[..2], [a, expected], "known {} {}= {}", a, name, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn min_max() {
+        for &(a, b) in &[(6, 10), (10, 6), (7, 7)] {
+            let mem = run(|ctx| {
+                ctx.with_stack_alloc4(|ctx, a_ptr, b_ptr, min, max| {
+                    ctx.set(a_ptr, a);
+                    ctx.set(b_ptr, b);
+                    ctx.forget(a_ptr);
+                    ctx.forget(b_ptr);
+                    ctx.min(a_ptr, b_ptr, min);
+                    ctx.max(a_ptr, b_ptr, max);
+                })
+            });
+
+            assert_eq!(mem[..4], [a, b, a.min(b), a.max(b)], "min/max of {} and {}", a, b);
+        }
+    }
+
+    #[test]
+    fn run_fsm() {
+        // Toggles between states 0 and 1 on '1' and counts how often state 1 is entered.
         let code = gen(|ctx| {
-            ctx.set(&Ptr::new(3), 13);
+            ctx.with_stack_alloc3(|ctx, state, input, entered| {
+                let count = |ctx: &mut Context| ctx.increment(entered);
+                let transitions = [
+                    Transition { state: 0, input: b'1', next_state: 1, action: Some(&count) },
+                    Transition { state: 1, input: b'1', next_state: 0, action: None },
+                    Transition { state: 1, input: b'0', next_state: 1, action: None },
+                ];
+
+                ctx.set(state, 0);
+                ctx.set(entered, 0);
+                ctx.run_fsm(state, input, &transitions);
+            })
         });
 
-        assert_eq!(code, ">>>[-]+++++++++++++");
+        for &(input, state, entered) in &[("10110", 1, 2), ("11", 0, 1), ("0", 0, 0), ("", 0, 0)] {
+            let mut vm = VM::new();
+            vm.set_input(input);
+            vm.run(&code).unwrap();
+            assert_eq!(vm.mem()[..3], [state, 0, entered], "{:?}", input);
+        }
     }
 
     #[test]
-    fn not() {
+    fn fsm_step() {
+        // Toggles between states 0 and 1 on input 1 and counts how often state 1 is entered.
         let mem = run(|ctx| {
-            ctx.with_stack_alloc2(|ctx, a, b| {
-                ctx.set_bool(a, false);
-                ctx.set_bool(b, true);
-                ctx.not(a);
-                ctx.not(b);
+            ctx.with_stack_alloc3(|ctx, state, input, entered| {
+                let count = |ctx: &mut Context| ctx.increment(entered);
+                let transitions = [
+                    Transition { state: 0, input: 1, next_state: 1, action: Some(&count) },
+                    Transition { state: 1, input: 1, next_state: 0, action: None },
+                    Transition { state: 1, input: 0, next_state: 1, action: None },
+                ];
+
+                ctx.set(state, 0);
+                ctx.set(entered, 0);
+
+                for &byte in &[1, 0, 1, 1, 0] {
+                    ctx.set(input, byte);
+                    ctx.forget(input);
+                    ctx.fsm_step(state, input, &transitions);
+                }
             })
         });
 
-        assert_eq!(mem[..2], [1, 0]);
+        assert_eq!(mem[..3], [1, 0, 2]);
     }
 
     #[test]
-    fn or() {
-        let mem = run(|ctx| {
-            ctx.with_stack_alloc2(|ctx, false_, true_| {
-                ctx.with_stack_alloc4(|ctx, a, b, c, d| {
-                    ctx.set_bool(false_, false);
-                    ctx.set_bool(true_, true);
-                    ctx.or(false_, false_, a);
-                    ctx.or(false_,  true_, b);
-                    ctx.or( true_, false_, c);
-                    ctx.or( true_,  true_, d);
+    fn abs_diff() {
+        for &(a, b, diff) in &[(9, 4, 5), (4, 9, 5), (7, 7, 0)] {
+            let mem = run(|ctx| {
+                ctx.with_stack_alloc3(|ctx, a_ptr, b_ptr, target| {
+                    ctx.set(a_ptr, a);
+                    ctx.set(b_ptr, b);
+                    ctx.forget(a_ptr);
+                    ctx.forget(b_ptr);
+                    ctx.abs_diff(a_ptr, b_ptr, target);
                 })
-            })
+            });
+
+            assert_eq!(mem[..3], [a, b, diff], "|{} - {}|", a, b);
+        }
+    }
+
+    #[test]
+    fn if_else_consume() {
+        for &cond_value in &[false, true] {
+            let mem = run(|ctx| {
+                ctx.with_stack_alloc2(|ctx, then, else_| {
+                    let cond = ctx.stack_alloc();
+                    ctx.set_bool(&cond, cond_value);
+                    ctx.forget(&cond);
+                    ctx.if_else_consume(cond, |ctx| ctx.set(then, 1), |ctx| ctx.set(else_, 1));
+                })
+            });
+
+            assert_eq!(mem[..3], [cond_value as u8, !cond_value as u8, 0]);
+        }
+
+        let copying = gen(|ctx| ctx.with_stack_alloc(|ctx, cond| {
+            ctx.read(cond);
+            ctx.if_else(cond, |ctx| ctx.emit("."), |_| {});
+        }));
+        let consuming = gen(|ctx| {
+            let cond = ctx.stack_alloc();
+            ctx.read(&cond);
+            ctx.if_else_consume(cond, |ctx| ctx.emit("."), |_| {});
         });
 
-        assert_eq!(mem[..6], [0, 1, 0, 1, 1, 1]);
+        assert!(consuming.len() < copying.len());
     }
 
     #[test]
-    fn xor() {
+    fn swap() {
         let mem = run(|ctx| {
-            ctx.with_stack_alloc2(|ctx, false_, true_| {
-                ctx.with_stack_alloc4(|ctx, a, b, c, d| {
-                    ctx.set_bool(false_, false);
-                    ctx.set_bool(true_, true);
-                    ctx.xor(false_, false_, a);
-                    ctx.xor(false_,  true_, b);
-                    ctx.xor( true_, false_, c);
-                    ctx.xor( true_,  true_, d);
-                })
+            ctx.with_stack_alloc2(|ctx, a, b| {
+                ctx.set(a, 3);
+                ctx.set(b, 7);
+                ctx.forget(b);
+                ctx.swap(a, b);
+
+                assert_eq!(ctx.value(a), None);
+                assert_eq!(ctx.value(b), Some(3));
             })
         });
 
-        assert_eq!(mem[..6], [0, 1, 1, 0, 0, 1]);
+        assert_eq!(mem[..2], [7, 3]);
+
+        let code = gen(|ctx| {
+            ctx.with_stack_alloc(|ctx, a| ctx.swap(a, a));
+        });
+
+        assert_eq!(code, "");
     }
 
     #[test]
-    fn and() {
-        let mem = run(|ctx| {
-            ctx.with_stack_alloc2(|ctx, false_, true_| {
-                ctx.with_stack_alloc4(|ctx, a, b, c, d| {
-                    ctx.set_bool(false_, false);
-                    ctx.set_bool(true_, true);
-                    ctx.and(false_, false_, a);
-                    ctx.and(false_,  true_, b);
-                    ctx.and( true_, false_, c);
-                    ctx.and( true_,  true_, d);
+    fn greater_than_preserves_operands() {
+        for a in 0..8 {
+            for b in 0..8 {
+                let mem = run(|ctx| {
+                    ctx.with_stack_alloc3(|ctx, a_ptr, b_ptr, target| {
+                        ctx.set(a_ptr, a);
+                        ctx.set(b_ptr, b);
+                        ctx.forget(a_ptr);
+                        ctx.forget(b_ptr);
+                        ctx.greater_than(a_ptr, b_ptr, target);
+                    })
+                });
+
+                assert_eq!(mem[..3], [a, b, (a > b) as u8], "{} > {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn classify() {
+        for &(value, bucket) in &[(0, 0), (9, 0), (10, 1), (19, 1), (25, 2), (30, 3), (42, 3)] {
+            let mem = run(|ctx| {
+                ctx.with_stack_alloc2(|ctx, value_ptr, bucket_ptr| {
+                    ctx.set(value_ptr, value);
+                    ctx.forget(value_ptr);
+                    ctx.classify(value_ptr, &[10, 20, 30], bucket_ptr);
                 })
+            });
+
+            assert_eq!(mem[..2], [value, bucket], "classifying {}", value);
+        }
+    }
+
+    #[test]
+    fn value_bool() {
+        gen(|ctx| {
+            ctx.with_stack_alloc3(|ctx, false_, true_, unknown| {
+                ctx.set_bool(false_, false);
+                ctx.set_bool(true_, true);
+                ctx.read(unknown);
+
+                assert_eq!(ctx.value_bool(false_), Some(false));
+                assert_eq!(ctx.value_bool(true_), Some(true));
+                assert_eq!(ctx.value_bool(unknown), None);
             })
         });
+    }
+
+    #[test]
+    fn print_str() {
+        let steps = |code: &str| code.chars().filter(|&c| c == '+' || c == '-').count();
+        let separate = steps(&gen(|ctx| ctx.print_str("Hello, "))) + steps(&gen(|ctx| ctx.print_str("world!")));
+        let shared = gen(|ctx| {
+            ctx.print_str("Hello, ");
+            ctx.print_str("world!");
+        });
 
-        assert_eq!(mem[..6], [0, 1, 0, 0, 0, 1]);
+        assert!(steps(&shared) < separate);
+        assert_eq!(shared.matches('.').count(), 13);
     }
 
     #[test]
-    fn add() {
-        let mem = run(|ctx| {
-            ctx.with_stack_alloc4(|ctx, a, b, c, d| {
-                ctx.set(a, 6);
-                ctx.set(b, 7);
-                ctx.set(c, 8);
-                ctx.set(d, 9);
-                ctx.add(a, b);
-                ctx.add(d, c);
+    fn print_str_using() {
+        let text = "Hello, world!\n";
+        let relative = gen(|ctx| {
+            ctx.with_stack_alloc(|ctx, scratch| ctx.print_str_using(scratch, text))
+        });
+        let naive = gen(|ctx| {
+            ctx.with_stack_alloc(|ctx, scratch| {
+                for &byte in text.as_bytes() {
+                    ctx.clear(scratch);
+                    ctx.increment_by(scratch, byte);
+                    ctx.print(scratch);
+                }
             })
         });
+        assert!(relative.len() < naive.len(), "{} >= {}", relative.len(), naive.len());
 
-        assert_eq!(mem[..4], [13, 0, 0, 17]);
+        let output = run_output(|ctx| {
+            ctx.with_stack_alloc(|ctx, scratch| {
+                ctx.set(scratch, 200);
+                ctx.print_str_using(scratch, text);
+            })
+        });
+        assert_eq!(output, text);
     }
 
     #[test]
-    fn mul() {
-        let mem = run(|ctx| {
+    fn print_bits() {
+        let output = run_output(|ctx| {
             ctx.with_stack_alloc4(|ctx, a, b, c, d| {
-                ctx.set(a, 6);
-                ctx.set(b, 7);
-                ctx.set(c, 8);
-                ctx.set(d, 9);
-                ctx.mul(a, b);
-                ctx.mul(d, c);
+                ctx.set(a, 1);
+                ctx.set(b, 0);
+                ctx.set(c, 7);
+                ctx.set(d, 1);
+                ctx.forget_known_values();
+                ctx.print_bits(a, 4);
+                ctx.set(c, 0);
+                ctx.print_bits(a, 4);
             })
         });
 
-        assert_eq!(mem[..4], [42, 7, 8, 72]);
+        assert_eq!(output, "10111001");
     }
 
+    #[test]
+    fn print_u8_decimal() {
+        for &value in &[0, 7, 10, 42, 100, 105, 255] {
+            let output = run_output(|ctx| {
+                ctx.with_stack_alloc(|ctx, ptr| {
+                    ctx.set(ptr, value);
+                    ctx.forget(ptr);
+                    ctx.print_u8_decimal(ptr);
+                    ctx.print_newline();
+                    ctx.set(ptr, value);
+                    ctx.print_u8_decimal(ptr);
+                })
+            });
+
+            assert_eq!(output, format!("{}\n{}", value, value));
+
+            let mem = run(|ctx| {
+                ctx.with_stack_alloc(|ctx, ptr| {
+                    ctx.set(ptr, value);
+                    ctx.forget(ptr);
+                    ctx.print_u8_decimal(ptr);
+                })
+            });
+            assert_eq!(mem[0], value);
+        }
+    }
 
     #[test]
-    fn sub() {
-        let mem = run(|ctx| {
-            ctx.with_stack_alloc4(|ctx, a, b, c, d| {
-                ctx.set(a, 9);
-                ctx.set(b, 8);
-                ctx.set(c, 6);
-                ctx.set(d, 7);
-                ctx.sub(a, b);
-                ctx.sub(d, c);
+    fn print_countdown() {
+        for &(from, expected) in &[(3, "3\n2\n1\n"), (0, ""), (11, "11\n10\n9\n8\n7\n6\n5\n4\n3\n2\n1\n")] {
+            let output = run_output(|ctx| {
+                ctx.with_stack_alloc(|ctx, ptr| {
+                    ctx.set(ptr, from);
+                    ctx.forget(ptr);
+                    ctx.print_countdown(ptr);
+                })
+            });
+
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn read_u8_decimal() {
+        let code = gen(|ctx| {
+            ctx.with_stack_alloc2(|ctx, target, rest| {
+                ctx.read_u8_decimal(target);
+                ctx.read(rest);
             })
         });
 
-        assert_eq!(mem[..4], [1, 0, 0, 1]);
+        for &(input, expected, rest) in &[("42\n", 42, 0), ("7x9", 7, b'9'), ("255 ", 255, 0), ("\n", 0, 0), ("0", 0, 0)] {
+            let mut vm = VM::new();
+            vm.set_input(input);
+            vm.run(&code).unwrap();
+            assert_eq!(vm.mem()[..2], [expected, rest], "{:?}", input);
+        }
     }
 
     #[test]
-    fn greater_than() {
-        let mem = run(|ctx| {
-            ctx.with_stack_alloc5(|ctx, a, b, r1, r2, r3| {
-                ctx.set(a, 6);
-                ctx.set(b, 10);
-                ctx.greater_than(a, b, r1);
-                ctx.greater_than(b, a, r2);
-                ctx.greater_than(a, a, r3);
+    fn read_u8_decimal_checked() {
+        let code = gen(|ctx| {
+            ctx.with_stack_alloc3(|ctx, target, ok, rest| {
+                ctx.read_u8_decimal_checked(target, ok);
+                ctx.read(rest);
             })
         });
 
-        assert_eq!(mem[..5], [6, 10, 0, 1, 0]);
+        for &(input, ok, rest) in &[("42", 1, 0), ("42\n7", 1, b'7'), ("4x", 0, 0), ("x4\n", 0, 0), ("", 0, 0), ("\n", 0, 0)] {
+            let mut vm = VM::new();
+            vm.set_input(input);
+            vm.run(&code).unwrap();
+            assert_eq!(vm.mem()[1..3], [ok, rest], "{:?}", input);
+
+            if ok == 1 {
+                assert_eq!(vm.mem()[0], 42);
+            }
+        }
+    }
+
+    #[test]
+    fn echo_loop() {
+        let code = gen(|ctx| ctx.echo_loop());
+
+        assert_eq!(code, ",[.,]");
+
+        let mut vm = VM::new();
+        vm.set_input("cat");
+        vm.run(&code).unwrap();
+        assert_eq!(vm.output(), b"cat");
+    }
+
+    #[test]
+    fn code() {
+        let mut code = String::new();
+        let mut ctx = Context::new(&mut code);
+
+        ctx.increment(&Ptr::new(1));
+        assert_eq!(ctx.code(), ">+");
+
+        ctx.print(&Ptr::new(0));
+        assert_eq!(ctx.code(), ">+<.");
     }
 
     #[test]
@@ -850,7 +3517,18 @@ mod tests {
 
         println!("code: {}", code);
 
-        vm.run(&code);
+        vm.run(&code).unwrap();
         vm.mem().to_vec()
     }
+
+    fn run_output<F>(f: F) -> String
+    where
+        F: FnOnce(&mut Context),
+    {
+        let code = gen(f);
+        let mut vm = VM::new();
+
+        vm.run(&code).unwrap();
+        vm.output_string().into_owned()
+    }
 }
\ No newline at end of file
@@ -0,0 +1,139 @@
+//! Optimizations on generated brainfuck code.
+
+use std::collections::HashSet;
+
+/// Removes adjacent `+-`, `-+`, `<>` and `><` pairs.
+/// A pair that only becomes adjacent after removing the pairs between it, like `++--`,
+/// is removed as well, so the result has no canceling pairs left.
+/// Nothing is moved across loops or I/O.
+pub fn optimize(code: &str) -> String {
+    let mut out = String::with_capacity(code.len());
+
+    for op in code.chars() {
+        match (out.chars().last(), op) {
+            (Some('+'), '-') | (Some('-'), '+') |
+            (Some('<'), '>') | (Some('>'), '<') => {
+                out.pop();
+            }
+            _ => out.push(op),
+        }
+    }
+
+    out
+}
+
+/// Replaces every run of `<` and `>` by the shortest run that moves as far.
+pub fn fold_moves(code: &str) -> String {
+    let mut out = String::with_capacity(code.len());
+    let mut offset = 0isize;
+
+    for op in code.chars() {
+        match op {
+            '>' => offset += 1,
+            '<' => offset -= 1,
+            _ => {
+                push_move(&mut out, offset);
+                offset = 0;
+                out.push(op);
+            }
+        }
+    }
+
+    push_move(&mut out, offset);
+
+    out
+}
+
+fn push_move(out: &mut String, offset: isize) {
+    let direction = if offset > 0 { ">" } else { "<" };
+    out.push_str(&direction.repeat(offset.unsigned_abs()));
+}
+
+/// Removes `[-]` from cells that are known to be zero already.
+/// A cell is known to be zero after `[-]` until it is changed.
+/// Nothing is known at the start of the code and inside or after any other loop.
+pub fn remove_redundant_clears(code: &str) -> String {
+    let mut out = String::with_capacity(code.len());
+    let mut zeros = HashSet::new();
+    let mut addr = 0isize;
+    let mut rest = code;
+
+    while let Some(op) = rest.chars().next() {
+        if rest.starts_with("[-]") {
+            if zeros.insert(addr) {
+                out.push_str("[-]");
+            }
+
+            rest = &rest[3..];
+            continue;
+        }
+
+        match op {
+            '>' => addr += 1,
+            '<' => addr -= 1,
+            '+' | '-' | ',' => {
+                zeros.remove(&addr);
+            }
+            '[' | ']' => zeros.clear(),
+            _ => {}
+        }
+
+        out.push(op);
+        rest = &rest[op.len_utf8()..];
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Context;
+    use minibf::VM;
+
+    #[test]
+    fn cancel_pairs() {
+        assert_eq!(optimize("+-"), "");
+        assert_eq!(optimize("++--"), "");
+        assert_eq!(optimize("+>-<"), "+>-<");
+        assert_eq!(optimize("[+-]"), "[]");
+        assert_eq!(optimize("+[-]+"), "+[-]+");
+        assert_eq!(optimize(">><<<.>"), "<.>");
+        assert_eq!(optimize("+.-"), "+.-");
+    }
+
+    #[test]
+    fn fold_moves() {
+        assert_eq!(super::fold_moves(">>><"), ">>");
+        assert_eq!(super::fold_moves("><"), "");
+        assert_eq!(super::fold_moves("<<>+>><<<[>><]."), "<+<[>].");
+        assert_eq!(super::fold_moves("+-"), "+-");
+
+        let mut ctx = Context::owned();
+        ctx.with_stack_alloc3(|ctx, a, b, c| {
+            ctx.set(c, 3);
+            ctx.set(a, 2);
+            ctx.forget(a);
+            ctx.mov(b, a);
+            ctx.greater_than(c, b, a);
+        });
+        let code = ctx.finish();
+        let folded = super::fold_moves(&code);
+        assert_eq!(super::fold_moves(&folded), folded);
+
+        let (mut vm, mut folded_vm) = (VM::new(), VM::new());
+        vm.run(&code).unwrap();
+        folded_vm.run(&folded).unwrap();
+        assert_eq!(vm.mem()[..3], folded_vm.mem()[..3]);
+    }
+
+    #[test]
+    fn remove_redundant_clears() {
+        assert_eq!(super::remove_redundant_clears("[-][-]"), "[-]");
+        assert_eq!(super::remove_redundant_clears(">[-]<[-]>[-]<[-]"), ">[-]<[-]><");
+        assert_eq!(super::remove_redundant_clears("[-]+[-]"), "[-]+[-]");
+        assert_eq!(super::remove_redundant_clears("[-],[-]"), "[-],[-]");
+        assert_eq!(super::remove_redundant_clears("[-][>][-]"), "[-][>][-]");
+        assert_eq!(super::remove_redundant_clears("[-].[-]"), "[-].");
+    }
+}
@@ -1,115 +1,470 @@
 use crate::ir::*;
 use crate::{Context, Ptr};
+use log::trace;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
 
 pub type Result<T = (), E = Error> = std::result::Result<T, E>;
-pub type Error = Box<std::error::Error>;
+pub type Error = Box<dyn std::error::Error>;
 
 pub fn trans(ir: &IR) -> Result<String> {
+    Ok(trans_with_options(ir, &Options::default())?.code)
+}
+
+/// Translates `ir` and also returns the cell of every variable, see `Translation::symbols`.
+pub fn trans_with_symbols(ir: &IR) -> Result<(String, HashMap<String, isize>)> {
+    let translation = trans_with_options(ir, &Options::default())?;
+    Ok((translation.code, translation.symbols))
+}
+
+/// Translates `ir` and also returns where each variable lives, see `DebugMap`.
+pub fn trans_with_map(ir: &IR) -> Result<(String, DebugMap)> {
+    let translation = trans_with_options(ir, &Options::default())?;
+    Ok((translation.code, translation.debug_map))
+}
+
+pub fn trans_with_options(ir: &IR, options: &Options) -> Result<Translation> {
+    let mut diagnostics = Vec::new();
+    let live_stmts = eliminate_dead_stores(ir.stmts.clone(), &mut diagnostics);
+    let stmts = if options.eliminate_dead_stores { &live_stmts } else { &ir.stmts };
+
     let mut code = String::new();
     let mut context = Context::new(&mut code);
-    Trans::new(&mut context).run(ir)?;
+    let (symbols, debug_map) = Trans::new(&mut context, options.reorder_statements).run(&ir.config, stmts)?;
+
+    Ok(Translation {
+        code,
+        diagnostics,
+        symbols,
+        debug_map,
+    })
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Drop declarations of variables that are never read, along with all stores to them.
+    /// Off by default, since a variable's final cell is the only way to observe its value.
+    pub eliminate_dead_stores: bool,
+    /// Translate independent assignments in the order that needs the least seeking.
+    pub reorder_statements: bool,
+}
+
+#[derive(Debug)]
+pub struct Translation {
+    pub code: String,
+    pub diagnostics: Vec<Diagnostic>,
+    /// The cell of every declared variable.
+    /// If a name is declared more than once, e.g. by shadowing or in a nested scope,
+    /// the declaration translated last wins. Cells of variables whose scope has ended
+    /// may have been reused by later variables.
+    pub symbols: HashMap<String, isize>,
+    pub debug_map: DebugMap,
+}
+
+/// Every scope of a translated program with the variables declared in it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugMap {
+    /// In the order the scopes were entered. The first one is the program's top level.
+    pub scopes: Vec<DebugScope>,
+}
+
+impl DebugMap {
+    /// Variables that are in scope at `pos` in the generated code, innermost scope first.
+    /// Shadowed variables are included after the variables shadowing them.
+    pub fn vars_at(&self, pos: usize) -> Vec<&DebugVar> {
+        let mut vars = Vec::new();
+        let mut scope = self.scopes.iter().rposition(|scope| scope.code.contains(&pos));
 
-    Ok(code)
+        while let Some(index) = scope {
+            let DebugScope { parent, vars: scope_vars, .. } = &self.scopes[index];
+            vars.extend(scope_vars.iter().rev().filter(|var| var.declared_at <= pos));
+            scope = *parent;
+        }
+
+        vars
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebugScope {
+    /// Index of the enclosing scope in `DebugMap::scopes`.
+    pub parent: Option<usize>,
+    /// The part of the generated code that runs inside the scope.
+    pub code: Range<usize>,
+    /// In declaration order.
+    pub vars: Vec<DebugVar>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebugVar {
+    pub name: String,
+    pub addr: isize,
+    /// Position in the generated code from which on the variable is in scope.
+    pub declared_at: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Diagnostic {
+    UnusedVariable(Ident),
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Diagnostic::UnusedVariable(name) => write!(f, "Variable '{}' is never read", &**name),
+        }
+    }
 }
 
 struct Trans<'ctx> {
     context: &'ctx mut Context<'ctx>,
     scopes: Vec<Scope>,
+    symbols: HashMap<String, isize>,
+    reorder_statements: bool,
+    /// Flags of the enclosing loops that contain `break` or `continue`, innermost last.
+    loops: Vec<LoopFlags>,
+    debug_map: DebugMap,
+    /// Index into `debug_map.scopes` for each entry of `scopes`.
+    debug_scopes: Vec<usize>,
 }
 
 impl<'ctx> Trans<'ctx> {
-    fn new(context: &'ctx mut Context<'ctx>) -> Self {
+    fn new(context: &'ctx mut Context<'ctx>, reorder_statements: bool) -> Self {
         Self {
             context,
             scopes: Vec::new(),
+            symbols: HashMap::new(),
+            reorder_statements,
+            loops: Vec::new(),
+            debug_map: DebugMap::default(),
+            debug_scopes: Vec::new(),
         }
     }
 
-    fn run(mut self, ir: &IR) -> Result {
+    fn run(mut self, config: &Config, stmts: &[Statement]) -> Result<(HashMap<String, isize>, DebugMap)> {
+        if config.cell_width != CellWidth::U8 {
+            Err(format!("Cell width {:?} is not supported, only u8 cells are", config.cell_width))?;
+        }
+
         self.push_scope();
+        self.trans_block(stmts)?;
+        self.pop_scope();
+
+        let cells = self.context.stack_size();
+        if cells > config.tape_size {
+            Err(format!("Program needs {} cells, but the tape only has {}", cells, config.tape_size))?;
+        }
+
+        Ok((self.symbols, self.debug_map))
+    }
+
+    fn trans_block(&mut self, stmts: &[Statement]) -> Result {
+        // Whatever follows a `break` or `continue` only runs while the loop iteration is still running.
+        if let Some(i) = stmts.iter().position(may_jump) {
+            if i + 1 < stmts.len() {
+                self.trans_block(&stmts[..=i])?;
+
+                let running = &self.context.stack_alloc();
+                let loop_running = &self.innermost_loop("break")?.running.clone();
+                self.context.copy(loop_running, running);
 
-        for stmt in &ir.stmts {
+                return self.trans_if_set(running, |trans| trans.trans_block(&stmts[i + 1..]));
+            }
+        }
+
+        if !self.reorder_statements {
+            for stmt in stmts {
+                self.trans_stmt(stmt)?;
+            }
+
+            return Ok(());
+        }
+
+        let mut run = Vec::new();
+
+        for stmt in stmts {
+            match stmt {
+                Statement::Assign(_) |
+                Statement::AddAssign(_) |
+                Statement::SubAssign(_) |
+                Statement::MulAssign(_) |
+                Statement::DivAssign(_) |
+                Statement::Read(_) => run.push(stmt),
+                stmt => {
+                    self.trans_run(std::mem::take(&mut run))?;
+                    self.trans_stmt(stmt)?;
+                }
+            }
+        }
+
+        self.trans_run(run)
+    }
+
+    /// Translates a run of assignments and reads, each time picking the one that needs the fewest seeks
+    /// among those that don't depend on a statement that is still pending.
+    fn trans_run(&mut self, mut pending: Vec<&Statement>) -> Result {
+        while !pending.is_empty() {
+            let mut next = 0;
+            let mut fewest_seeks = usize::MAX;
+
+            for i in 0..pending.len() {
+                if pending[..i].iter().any(|earlier| conflicts(earlier, pending[i])) {
+                    continue;
+                }
+
+                let seeks = self.seeks_needed(pending[i])?;
+                if seeks < fewest_seeks {
+                    next = i;
+                    fewest_seeks = seeks;
+                }
+            }
+
+            let stmt = pending.remove(next);
             self.trans_stmt(stmt)?;
         }
 
-        self.pop_scope();
         Ok(())
     }
 
+    /// Number of `<` and `>` that translating `stmt` right now would emit.
+    fn seeks_needed(&self, stmt: &Statement) -> Result<usize> {
+        let mut code = String::new();
+        let mut context = self.context.fork(&mut code);
+        let mut trial = Trans {
+            context: &mut context,
+            scopes: self.scopes.clone(),
+            symbols: HashMap::new(),
+            reorder_statements: false,
+            loops: Vec::new(),
+            debug_map: DebugMap::default(),
+            debug_scopes: Vec::new(),
+        };
+        trial.trans_stmt(stmt)?;
+
+        Ok(code.chars().filter(|&c| c == '<' || c == '>').count())
+    }
+
     fn trans_stmt(&mut self, stmt: &Statement) -> Result {
-        Ok(match stmt {
+        match stmt {
             Statement::Decl(Decl { name, value }) => {
                 let ptr = self.context.stack_alloc();
+                // The initializer still sees a variable that the new one shadows.
+                let value = value.as_ref().map(|value| self.trans_expr(value)).transpose()?;
                 self.decl_var(name.clone(), &ptr);
 
                 if let Some(value) = value {
-                    let value = self.trans_expr(value)?;
                     self.context.mov(&ptr, &value);
                 }
             }
             Statement::Assign(Assign { name, value }) => {
                 let value = self.trans_expr(value)?;
-                let ptr = self.resolve_var(name)?;
+                let ptr = self.resolve_assign_target(name)?;
                 self.context.mov(&ptr, &value);
             }
             Statement::AddAssign(AddAssign { name, value }) => {
                 let value = self.trans_expr(value)?;
-                let ptr = self.resolve_var(name)?;
+                let ptr = self.resolve_assign_target(name)?;
                 self.context.add(&ptr, &value);
             }
+            Statement::SubAssign(SubAssign { name, value }) => {
+                let value = self.trans_expr(value)?;
+                let ptr = self.resolve_assign_target(name)?;
+                self.context.sub(&ptr, &value);
+            }
+            Statement::MulAssign(MulAssign { name, value }) => {
+                let value = self.trans_expr(value)?;
+                let ptr = self.resolve_assign_target(name)?;
+                self.context.mul_assign(&ptr, &value);
+            }
+            Statement::DivAssign(DivAssign { name, value }) => {
+                let value = self.trans_expr(value)?;
+                let ptr = self.resolve_assign_target(name)?;
+                let quotient = self.context.stack_alloc();
+                let remainder = self.context.stack_alloc();
+                self.context.div_mod(&ptr, &value, &quotient, &remainder);
+                self.context.mov(&ptr, &quotient);
+            }
             Statement::While(While { cond, body }) => self.trans_stmt_while(cond, body)?,
             Statement::If(if_) => self.trans_stmt_if(if_)?,
-        })
+            Statement::Read(name) => {
+                let ptr = self.resolve_assign_target(name)?;
+                self.context.read(&ptr);
+            }
+            Statement::Break => {
+                let LoopFlags { running, broken } = self.innermost_loop("break")?.clone();
+                self.context.clear(&running);
+                self.context.set_bool(&broken, true);
+            }
+            Statement::Continue => {
+                let running = self.innermost_loop("continue")?.running.clone();
+                self.context.clear(&running);
+            }
+            Statement::Call(call) => {
+                self.trans_call(call)?;
+            }
+        }
+
+        Ok(())
     }
 
     fn trans_stmt_while(&mut self, cond: &Expr, body: &[Statement]) -> Result {
-        let tmp = self.trans_expr(cond)?;
-        self.context.seek(&tmp);
+        // The loop has to start and end on the same cell, so the condition is
+        // recomputed into this one instead of wherever its temporaries land.
+        let cond_cell = &self.context.stack_alloc();
+        let has_jumps = body.iter().any(may_jump);
+        let flags = if has_jumps {
+            let flags = LoopFlags {
+                running: self.context.stack_alloc(),
+                broken: self.context.stack_alloc(),
+            };
+            self.context.set_bool(&flags.broken, false);
+            Some(flags)
+        } else {
+            None
+        };
+
+        self.trans_expr_into(cond, cond_cell)?;
+        self.context.seek(cond_cell);
         self.context.emit("[");
         self.context.forget_known_values();
 
+        if let Some(flags) = flags {
+            self.context.set_bool(&flags.running, true);
+            self.loops.push(flags);
+        }
+
         self.push_scope();
+        self.trans_block(body)?;
+        self.pop_scope();
 
-        for stmt in body {
-            self.trans_stmt(stmt)?;
+        let flags = if has_jumps { self.loops.pop() } else { None };
+
+        match flags {
+            // After a `break`, the condition is neither evaluated nor allowed to restart the loop.
+            Some(LoopFlags { broken, .. }) => {
+                let not_broken = &self.context.stack_alloc();
+                self.context.copy(&broken, not_broken);
+                self.context.not(not_broken);
+                self.context.clear(cond_cell);
+                self.trans_if_set(not_broken, |trans| trans.trans_expr_into(cond, cond_cell))?;
+            }
+            None => self.trans_expr_into(cond, cond_cell)?,
         }
 
-        self.pop_scope();
+        self.context.seek(cond_cell);
+        self.context.emit("]");
+        self.context.forget_known_values();
+
+        Ok(())
+    }
+
+    /// Runs the code generated by `f` if `*flag` is non-zero.
+    /// Sideffect: *flag = 0
+    fn trans_if_set<F>(&mut self, flag: &Ptr, f: F) -> Result
+    where
+        F: FnOnce(&mut Self) -> Result,
+    {
+        self.context.seek(flag);
+        self.context.emit("[");
+        self.context.forget_known_values();
+
+        f(self)?;
 
-        drop(tmp);
-        let tmp = self.trans_expr(cond)?;
-        self.context.seek(&tmp);
+        self.context.clear(flag);
+        self.context.seek(flag);
         self.context.emit("]");
+        self.context.forget_known_values();
 
         Ok(())
     }
 
-    fn trans_stmt_if(&mut self, If { cond, body }: &If) -> Result {
+    fn innermost_loop(&self, keyword: &str) -> Result<&LoopFlags> {
+        self.loops.last()
+            .ok_or_else(|| format!("'{}' is only allowed inside of a loop", keyword).into())
+    }
+
+    /// Evaluates `expr` into `target`, freeing all intermediate cells.
+    fn trans_expr_into(&mut self, expr: &Expr, target: &Ptr) -> Result {
+        let value = self.trans_expr(expr)?;
+        self.context.mov(target, &value);
+
+        Ok(())
+    }
+
+    fn trans_stmt_if(&mut self, If { cond, body, else_body }: &If) -> Result {
         let cond = &self.trans_expr(cond)?;
         let tmp = &self.context.stack_alloc();
         self.context.copy(cond, tmp);
 
+        let run_else = if else_body.is_empty() {
+            None
+        } else {
+            let run_else = self.context.stack_alloc();
+            self.context.set_bool(&run_else, true);
+            Some(run_else)
+        };
+
         self.context.seek(tmp);
         self.context.emit("[");
         self.context.forget_known_values();
 
-        self.push_scope();
-
-        for stmt in body {
-            self.trans_stmt(stmt)?;
+        if let Some(run_else) = &run_else {
+            self.context.clear(run_else);
         }
 
+        self.push_scope();
+        self.trans_block(body)?;
         self.pop_scope();
 
-        self.context.decrement(tmp);
+        self.context.clear(tmp);
         self.context.seek(tmp);
         self.context.emit("]");
+        self.context.forget_known_values();
+
+        if let Some(run_else) = &run_else {
+            self.context.seek(run_else);
+            self.context.emit("[");
+            self.context.forget_known_values();
+
+            self.push_scope();
+            self.trans_block(else_body)?;
+            self.pop_scope();
+
+            self.context.clear(run_else);
+            self.context.seek(run_else);
+            self.context.emit("]");
+            self.context.forget_known_values();
+        }
 
         Ok(())
     }
 
+    /// Lowers `&&` and `||` by normalizing both operands to booleans and combining them.
+    /// Both operands are always evaluated; short-circuiting would only need
+    /// to guard the evaluation of `b` on the value of `a` here.
+    fn trans_logical<F>(&mut self, a: &Expr, b: &Expr, combine: F) -> Result<Ptr>
+    where
+        F: FnOnce(&mut Context<'ctx>, &Ptr, &Ptr, &Ptr),
+    {
+        let a = &self.trans_expr(a)?;
+        self.context.is_not_zero_destructive(a);
+        let b = &self.trans_expr(b)?;
+        self.context.is_not_zero_destructive(b);
+        let res = self.context.stack_alloc();
+
+        combine(self.context, a, b, &res);
+
+        Ok(res)
+    }
+
     fn trans_expr(&mut self, expr: &Expr) -> Result<Ptr> {
         use Expr::*;
+
+        // Expressions made only of constants are translated like a single literal.
+        let folded = expr.const_value().map(Const);
+        let expr = folded.as_ref().unwrap_or(expr);
+
         Ok(match expr {
             Const(value) => {
                 let ptr = self.context.stack_alloc();
@@ -134,6 +489,12 @@ impl<'ctx> Trans<'ctx> {
                 self.context.sub(&a, &b);
                 a
             }
+            Mul(a, b) => {
+                let a = self.trans_expr(a)?;
+                let b = self.trans_expr(b)?;
+                self.context.mul(&a, &b);
+                a
+            }
             Gt(a, b) => {
                 let a = &self.trans_expr(a)?;
                 let b = &self.trans_expr(b)?;
@@ -143,19 +504,106 @@ impl<'ctx> Trans<'ctx> {
 
                 res
             }
+            Lt(a, b) => {
+                let a = &self.trans_expr(a)?;
+                let b = &self.trans_expr(b)?;
+                let res = self.context.stack_alloc();
+
+                self.context.less_than(a, b, &res);
+
+                res
+            }
+            Eq(a, b) => {
+                let a = &self.trans_expr(a)?;
+                let b = &self.trans_expr(b)?;
+                let res = self.context.stack_alloc();
+
+                self.context.equals(a, b, &res);
+
+                res
+            }
+            Ne(a, b) => {
+                let a = &self.trans_expr(a)?;
+                let b = &self.trans_expr(b)?;
+                let res = self.context.stack_alloc();
+
+                self.context.not_equals(a, b, &res);
+
+                res
+            }
+            And(a, b) => self.trans_logical(a, b, Context::and)?,
+            Or(a, b) => self.trans_logical(a, b, Context::or)?,
+            Not(a) => {
+                let a = self.trans_expr(a)?;
+                self.context.is_zero_destructive(&a);
+                a
+            }
+            Call(call) => self.trans_call(call)?,
         })
     }
 
+    /// Lowers a call to one of the builtin functions.
+    /// There are no user defined functions, so every other name is an error.
+    fn trans_call(&mut self, Call { name, args }: &Call) -> Result<Ptr> {
+        let arity = match &**name {
+            "read_num" => 0,
+            "print_num" => 1,
+            "min" | "max" | "abs_diff" => 2,
+            _ => Err(format!("Unknown function '{}'", &**name))?,
+        };
+
+        if args.len() != arity {
+            Err(format!("Function '{}' takes {} arguments, but {} were given", &**name, arity, args.len()))?;
+        }
+
+        let args = args.iter()
+            .map(|arg| self.trans_expr(arg))
+            .collect::<Result<Vec<_>>>()?;
+        let res = self.context.stack_alloc();
+
+        match &**name {
+            "read_num" => self.context.read_u8_decimal(&res),
+            "print_num" => {
+                self.context.print_u8_decimal(&args[0]);
+                self.context.clear(&res);
+            }
+            "min" => self.context.min(&args[0], &args[1], &res),
+            "max" => self.context.max(&args[0], &args[1], &res),
+            "abs_diff" => self.context.abs_diff(&args[0], &args[1], &res),
+            name => Err(format!("BUG: unhandled builtin: {}", name))?,
+        }
+
+        Ok(res)
+    }
+
     fn push_scope(&mut self) {
+        let start = self.context.emitted_len();
+        self.debug_scopes.push(self.debug_map.scopes.len());
+        self.debug_map.scopes.push(DebugScope {
+            parent: self.debug_scopes.iter().rev().nth(1).copied(),
+            code: start..start,
+            vars: Vec::new(),
+        });
         self.scopes.push(Scope::new());
     }
 
     fn pop_scope(&mut self) {
-        println!("scopes: {:#?}", self.scopes);
+        trace!("scopes: {:#?}", self.scopes);
+        let index = self.debug_scopes.pop().unwrap();
+        self.debug_map.scopes[index].code.end = self.context.emitted_len();
         self.scopes.pop();
     }
 
     fn decl_var(&mut self, name: Ident, ptr: &Ptr) {
+        self.symbols.insert(name.to_string(), ptr.as_isize());
+
+        let index = *self.debug_scopes.last().unwrap();
+        self.debug_map.scopes[index].vars.push(DebugVar {
+            name: name.to_string(),
+            addr: ptr.as_isize(),
+            declared_at: self.context.emitted_len(),
+        });
+
         self.scopes.last_mut().unwrap().decl_var(name, ptr);
     }
 
@@ -170,9 +618,161 @@ impl<'ctx> Trans<'ctx> {
     fn resolve_var(&self, name: &Ident) -> Result<Ptr> {
         Ok(self.find_var(name)?.ptr.clone())
     }
+
+    /// Like `resolve_var`, but for variables that are about to be written.
+    fn resolve_assign_target(&self, name: &Ident) -> Result<Ptr> {
+        self.resolve_var(name)
+            .map_err(|_| undeclared_assign_target(name).into())
+    }
 }
 
-#[derive(Debug)]
+/// Removes declarations of variables that are never read, together with every store to them.
+/// A declaration counts as unread if no later statement of its block, nested bodies included,
+/// reads its name. Names aren't resolved to scopes, so a read of another variable with the same name
+/// keeps the declaration too, and there is no liveness analysis: a store is only removed with its variable.
+/// Nested bodies are handled first and declarations are visited last to first,
+/// so a variable that only feeds other dead variables is removed as well.
+/// Variables whose stores have side effects are reported but kept.
+fn eliminate_dead_stores(stmts: Vec<Statement>, diagnostics: &mut Vec<Diagnostic>) -> Vec<Statement> {
+    let mut stmts = stmts.into_iter()
+        .map(|stmt| match stmt {
+            Statement::While(While { cond, body }) => Statement::While(While {
+                cond,
+                body: eliminate_dead_stores(body, diagnostics),
+            }),
+            Statement::If(If { cond, body, else_body }) => Statement::If(If {
+                cond,
+                body: eliminate_dead_stores(body, diagnostics),
+                else_body: eliminate_dead_stores(else_body, diagnostics),
+            }),
+            stmt => stmt,
+        })
+        .collect::<Vec<_>>();
+
+    for i in (0..stmts.len()).rev() {
+        let (name, value) = match &stmts[i] {
+            Statement::Decl(Decl { name, value }) => (name.clone(), value),
+            _ => continue,
+        };
+        let rest = &stmts[i + 1..];
+
+        if rest.iter().any(|stmt| stmt.reads(&name)) {
+            continue;
+        }
+
+        diagnostics.push(Diagnostic::UnusedVariable(name.clone()));
+
+        let has_side_effects = value.as_ref().is_some_and(Expr::has_side_effects)
+            || rest.iter().any(|stmt| stores_have_side_effects(stmt, &name));
+
+        if !has_side_effects {
+            let rest = stmts.split_off(i + 1);
+            stmts.pop();
+            stmts.extend(remove_stores(rest, &name));
+        }
+    }
+
+    stmts
+}
+
+fn stores_have_side_effects(stmt: &Statement, name: &Ident) -> bool {
+    match stmt {
+        Statement::Decl(_) | Statement::Call(_) | Statement::Break | Statement::Continue => false,
+        // Consuming input is a side effect even if the byte is never used.
+        Statement::Read(target) => target == name,
+        Statement::Assign(Assign { name: target, value }) |
+        Statement::AddAssign(AddAssign { name: target, value }) |
+        Statement::SubAssign(SubAssign { name: target, value }) |
+        Statement::MulAssign(MulAssign { name: target, value }) |
+        Statement::DivAssign(DivAssign { name: target, value }) => {
+            target == name && value.has_side_effects()
+        }
+        Statement::While(While { body, .. }) => {
+            body.iter().any(|stmt| stores_have_side_effects(stmt, name))
+        }
+        Statement::If(If { body, else_body, .. }) => {
+            body.iter().chain(else_body).any(|stmt| stores_have_side_effects(stmt, name))
+        }
+    }
+}
+
+fn remove_stores(stmts: Vec<Statement>, name: &Ident) -> Vec<Statement> {
+    stmts.into_iter()
+        .filter_map(|stmt| Some(match stmt {
+            Statement::Assign(Assign { name: ref target, .. }) |
+            Statement::AddAssign(AddAssign { name: ref target, .. }) |
+            Statement::SubAssign(SubAssign { name: ref target, .. }) |
+            Statement::MulAssign(MulAssign { name: ref target, .. }) |
+            Statement::DivAssign(DivAssign { name: ref target, .. }) if target == name => return None,
+            Statement::While(While { cond, body }) => Statement::While(While {
+                cond,
+                body: remove_stores(body, name),
+            }),
+            Statement::If(If { cond, body, else_body }) => Statement::If(If {
+                cond,
+                body: remove_stores(body, name),
+                else_body: remove_stores(else_body, name),
+            }),
+            stmt => stmt,
+        }))
+        .collect()
+}
+
+/// Whether translating the two statements in swapped order could change the result:
+/// they write the same variable, one reads what the other writes, or both do I/O.
+fn conflicts(a: &Statement, b: &Statement) -> bool {
+    let (a_var, b_var) = (assigned_var(a), assigned_var(b));
+
+    a_var == b_var || a.reads(b_var) || b.reads(a_var) || (does_io(a) && does_io(b))
+}
+
+fn assigned_var(stmt: &Statement) -> &Ident {
+    match stmt {
+        Statement::Assign(Assign { name, .. }) |
+        Statement::AddAssign(AddAssign { name, .. }) |
+        Statement::SubAssign(SubAssign { name, .. }) |
+        Statement::MulAssign(MulAssign { name, .. }) |
+        Statement::DivAssign(DivAssign { name, .. }) |
+        Statement::Read(name) => name,
+        stmt => panic!("BUG: not an assignment: {:?}", stmt),
+    }
+}
+
+fn does_io(stmt: &Statement) -> bool {
+    match stmt {
+        Statement::Assign(Assign { value, .. }) |
+        Statement::AddAssign(AddAssign { value, .. }) |
+        Statement::SubAssign(SubAssign { value, .. }) |
+        Statement::MulAssign(MulAssign { value, .. }) |
+        Statement::DivAssign(DivAssign { value, .. }) => value.has_side_effects(),
+        _ => true,
+    }
+}
+
+/// Cells a loop containing `break` or `continue` uses to track its control flow.
+#[derive(Debug, Clone)]
+struct LoopFlags {
+    /// Cleared by `break` and `continue` for the rest of the iteration.
+    running: Ptr,
+    /// Set by `break`.
+    broken: Ptr,
+}
+
+pub(crate) fn undeclared_assign_target(name: &Ident) -> String {
+    format!("Cannot assign to undeclared variable '{0}', declare it first with 'let {0}'", &**name)
+}
+
+/// Whether `stmt` may leave the current iteration of the innermost loop.
+/// Loops nested in `stmt` handle their own `break` and `continue`.
+fn may_jump(stmt: &Statement) -> bool {
+    match stmt {
+        Statement::Break | Statement::Continue => true,
+        Statement::If(If { body, else_body, .. }) => body.iter().chain(else_body).any(may_jump),
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone)]
 struct Scope {
     variables: Vec<Var>,
 }
@@ -198,8 +798,531 @@ impl Scope {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Var {
     name: Ident,
     ptr: Ptr,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use minibf::VM;
+
+    #[test]
+    fn tape_size() {
+        let ir = IR::parse_str("
+            #tape 2
+            let x = 1
+            let y = 2
+        ").unwrap();
+        assert!(trans(&ir).is_err());
+
+        let ir = IR::parse_str("
+            #tape 3
+            let x = 1
+            let y = 2
+        ").unwrap();
+        assert!(trans(&ir).is_ok());
+    }
+
+    #[test]
+    fn constant_folding() {
+        let folded = trans(&IR::parse_str("
+            let y = 9
+            while y > 2 + 3 { y -= 1 }
+        ").unwrap()).unwrap();
+        let literal = trans(&IR::parse_str("
+            let y = 9
+            while y > 5 { y -= 1 }
+        ").unwrap()).unwrap();
+        assert_eq!(folded, literal);
+
+        let folded = trans(&IR::parse_str("
+            let y = 2
+            let x = (2 + 3) * 4
+        ").unwrap()).unwrap();
+        let runtime = trans(&IR::parse_str("
+            let y = 2
+            let x = (y + 3) * 4
+        ").unwrap()).unwrap();
+        assert!(folded.len() * 2 < runtime.len());
+
+        let run = |code: &str| {
+            let mut vm = VM::new();
+            vm.run(code).unwrap();
+            vm.mem()[..2].to_vec()
+        };
+        assert_eq!(run(&folded), [2, 20]);
+        assert_eq!(run(&folded), run(&runtime));
+    }
+
+    #[test]
+    fn mul() {
+        let ir = IR::parse_str("
+            let x = 6
+            let y = x * 7 + 1
+            let z = 2 * 3 * x
+        ").unwrap();
+        let mut vm = VM::new();
+        vm.run(trans(&ir).unwrap()).unwrap();
+
+        assert_eq!(vm.mem()[..3], [6, 43, 36]);
+    }
+
+    #[test]
+    fn comparisons() {
+        let ir = IR::parse_str("
+            let a = 3
+            let b = 5
+            let lt = a < b
+            let gt = a > b
+            let eq = a == b
+            let ne = a != b
+            let same = a == 3
+            let count = 0
+            while count < 4 {
+                count += 1
+            }
+            if a != b {
+                count += 10
+            }
+        ").unwrap();
+        let mut vm = VM::new();
+        vm.run(trans(&ir).unwrap()).unwrap();
+
+        assert_eq!(vm.mem()[..8], [3, 5, 1, 0, 0, 1, 1, 14]);
+    }
+
+    #[test]
+    fn logical() {
+        let ir = IR::parse_str("
+            let a = 0
+            let b = 0
+            let ands = 0
+            let ors = 0
+            let i = 0
+            while i < 4 {
+                let and = a && b
+                let or = a || b
+                ands = ands + ands + and
+                ors = ors + ors + or
+                b = b == 0
+                if b == 0 {
+                    a = 3
+                }
+                i += 1
+            }
+            let mixed = 0 && 2 || 5
+        ").unwrap();
+        let mut vm = VM::new();
+        vm.run(trans(&ir).unwrap()).unwrap();
+
+        // Truth tables over (a, b) = (0, 0), (0, 1), (3, 0), (3, 1), most significant bit first.
+        assert_eq!(vm.mem()[2..4], [0b0001, 0b0111]);
+        assert_eq!(vm.mem()[5], 1);
+    }
+
+    #[test]
+    fn while_compound_cond() {
+        let ir = IR::parse_str("
+            let a = 10
+            let b = 3
+            let count = 0
+            while a > b {
+                a -= 1
+                count += 1
+            }
+            let steps = 0
+            while a + steps < b * 4 && steps != 4 {
+                let scratch = steps * 2
+                steps += 1
+            }
+        ").unwrap();
+        let mut vm = VM::new();
+        vm.run(trans(&ir).unwrap()).unwrap();
+
+        assert_eq!(vm.mem()[..4], [3, 3, 7, 4]);
+    }
+
+    #[test]
+    fn break_early() {
+        let ir = IR::parse_str("
+            let i = 0
+            let sum = 0
+            while i < 100 {
+                i += 1
+                if i == 5 {
+                    break
+                }
+                sum += i
+            }
+            let after = 7
+        ").unwrap();
+        let mut vm = VM::new();
+        vm.run(trans(&ir).unwrap()).unwrap();
+
+        assert_eq!(vm.mem()[..3], [5, 10, 7]);
+
+        let ir = IR::parse_str("
+            let outer = 0
+            let inner_total = 0
+            while outer < 3 {
+                outer += 1
+                let inner = 0
+                while true {
+                    inner += 1
+                    if inner > 1 { break }
+                }
+                inner_total += inner
+            }
+        ").unwrap();
+        let mut vm = VM::new();
+        vm.run(trans(&ir).unwrap()).unwrap();
+
+        assert_eq!(vm.mem()[..2], [3, 6]);
+    }
+
+    #[test]
+    fn continue_skips_rest() {
+        let ir = IR::parse_str("
+            let i = 0
+            let odd = 0
+            let even = 0
+            while i != 6 {
+                i += 1
+                if i == 2 || i == 4 || i == 6 {
+                    even += 1
+                    continue
+                }
+                odd += 1
+            }
+        ").unwrap();
+        let mut vm = VM::new();
+        vm.run(trans(&ir).unwrap()).unwrap();
+
+        assert_eq!(vm.mem()[..3], [6, 3, 3]);
+    }
+
+    #[test]
+    fn jump_outside_of_loop() {
+        let ir = IR::parse_str("let x = 1 if x { break }").unwrap();
+        let err = trans(&ir).unwrap_err();
+        assert_eq!(err.to_string(), "'break' is only allowed inside of a loop");
+    }
+
+    #[test]
+    fn if_else() {
+        let ir = IR::parse_str("
+            let a = 0
+            let b = 0
+            let c = 0
+            if a {
+                b = 1
+            } else {
+                b = 2
+            }
+            a = 1
+            if a {
+                c = 3
+            } else {
+                c = 4
+            }
+            let x = 3
+            let d = 0
+            if x {
+                d += 1
+            } else {
+                d += 10
+            }
+            if x {
+                d += 100
+            }
+        ").unwrap();
+        let mut vm = VM::new();
+        vm.run(trans(&ir).unwrap()).unwrap();
+
+        assert_eq!(vm.mem()[..5], [1, 2, 3, 3, 101]);
+    }
+
+    #[test]
+    fn not() {
+        let ir = IR::parse_str("
+            let a = 0
+            let b = 7
+            let x = !a
+            let y = !b
+            let z = !a && !(b < 3)
+        ").unwrap();
+        let mut vm = VM::new();
+        vm.run(trans(&ir).unwrap()).unwrap();
+
+        assert_eq!(vm.mem()[2..5], [1, 0, 1]);
+    }
+
+    #[test]
+    fn bools() {
+        let ir = IR::parse_str("
+            let t = true
+            let f = false
+            let x = 0
+            if t && true != false {
+                x = 5
+            }
+        ").unwrap();
+        let mut vm = VM::new();
+        vm.run(trans(&ir).unwrap()).unwrap();
+
+        assert_eq!(vm.mem()[..3], [1, 0, 5]);
+    }
+
+    #[test]
+    fn compound_assign() {
+        let ir = IR::parse_str("
+            let a = 10
+            let b = 6
+            let c = 7
+            let d = 0
+            let e = 9
+            a -= 3
+            b *= c
+            c /= 2
+            e /= d
+            d = a
+            d -= 1
+            d *= 3
+            d /= 4
+        ").unwrap();
+        let mut vm = VM::new();
+        vm.run(trans(&ir).unwrap()).unwrap();
+
+        assert_eq!(vm.mem()[..5], [7, 42, 3, 4, 0]);
+    }
+
+    #[test]
+    fn read() {
+        let ir = IR::parse_str("
+            let x = 7
+            let y
+            read x
+            read y
+            y += 1
+        ").unwrap();
+        let mut vm = VM::new();
+        vm.set_input("Ab");
+        vm.run(trans(&ir).unwrap()).unwrap();
+
+        assert_eq!(vm.mem()[..2], [b'A', b'b' + 1]);
+    }
+
+    #[test]
+    fn symbols() {
+        let ir = IR::parse_str("
+            let x = 1
+            let y = 2
+            while x {
+                let z = y
+                x = 0
+            }
+            let w = 3
+        ").unwrap();
+        let (code, symbols) = trans_with_symbols(&ir).unwrap();
+
+        let mut vm = VM::new();
+        vm.run(code).unwrap();
+
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(vm.mem()[symbols["x"] as usize], 0);
+        assert_eq!(vm.mem()[symbols["y"] as usize], 2);
+        assert_eq!(vm.mem()[symbols["w"] as usize], 3);
+        assert_eq!(symbols["x"], 0);
+        assert_eq!(symbols["y"], 1);
+    }
+
+    #[test]
+    fn debug_map() {
+        let ir = IR::parse_str("
+            let x = 1
+            let y = 2
+            while x {
+                let z = y
+                let x = 5
+                x = 0
+            }
+            let w = 3
+        ").unwrap();
+        let (code, map) = trans_with_map(&ir).unwrap();
+        let vars = |index: usize| map.scopes[index].vars.iter()
+            .map(|var| (var.name.as_str(), var.addr))
+            .collect::<Vec<_>>();
+
+        assert_eq!(map.scopes.len(), 2);
+        assert_eq!(map.scopes[0].parent, None);
+        assert_eq!(map.scopes[0].code, 0..code.len());
+        assert_eq!(vars(0), [("x", 0), ("y", 1), ("w", 2)]);
+        assert_eq!(map.scopes[1].parent, Some(0));
+        assert_eq!(vars(1), [("z", 3), ("x", 4)]);
+
+        let inner_end = map.scopes[1].code.end;
+        let names_at = |pos| map.vars_at(pos).iter().map(|var| var.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names_at(inner_end - 1), ["x", "z", "y", "x"]);
+        assert_eq!(names_at(code.len() - 1), ["w", "y", "x"]);
+    }
+
+    #[test]
+    fn trans_is_silent_by_default() {
+        use log::{Log, Metadata, Record};
+        use std::sync::Mutex;
+
+        static MESSAGES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        /// Only keeps messages about this test's program, whatever other tests log meanwhile.
+        struct Recorder;
+
+        impl Log for Recorder {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &Record) {
+                let message = record.args().to_string();
+
+                if message.contains("silent_marker") {
+                    MESSAGES.lock().unwrap().push(message);
+                }
+            }
+
+            fn flush(&self) {}
+        }
+
+        // The max level is left alone, like in a program that never configures logging.
+        log::set_logger(&Recorder).unwrap();
+
+        let ir = IR::parse_str("let silent_marker = 1").unwrap();
+        trans(&ir).unwrap();
+
+        assert!(MESSAGES.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn undeclared_variables() {
+        let err = trans(&IR::parse_str("x = 1").unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "Cannot assign to undeclared variable 'x', declare it first with 'let x'");
+
+        let err = trans(&IR::parse_str("let y x *= 2").unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "Cannot assign to undeclared variable 'x', declare it first with 'let x'");
+
+        let err = trans(&IR::parse_str("let y y = x").unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "Variable 'x' is not in scope");
+    }
+
+    #[test]
+    fn unused_variable() {
+        let ir = IR::parse_str("let x = 1 + 2").unwrap();
+        let options = Options { eliminate_dead_stores: true, ..Options::default() };
+        let translation = trans_with_options(&ir, &options).unwrap();
+
+        assert_eq!(translation.code, "");
+        assert_eq!(translation.diagnostics.len(), 1);
+        assert_eq!(translation.diagnostics[0].to_string(), "Variable 'x' is never read");
+
+        let translation = trans_with_options(&ir, &Options::default()).unwrap();
+        assert_ne!(translation.code, "");
+        assert_eq!(translation.diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn dead_store_chain() {
+        let ir = IR::parse_str("
+            let x = 1
+            let y = x
+            y += 2
+            let z = 3
+            while z {
+                z = 0
+            }
+        ").unwrap();
+        let options = Options { eliminate_dead_stores: true, ..Options::default() };
+        let translation = trans_with_options(&ir, &options).unwrap();
+        let expected = trans(&IR::parse_str("
+            let z = 3
+            while z {
+                z = 0
+            }
+        ").unwrap()).unwrap();
+
+        assert_eq!(translation.code, expected);
+        assert_eq!(translation.diagnostics.len(), 2);
+    }
+
+    #[test]
+    fn reorder_statements() {
+        // Reads end on their variable, far below the temporaries the assignments work in,
+        // so grouping the reads saves the trips up and down the tape in between.
+        let ir = IR::parse_str("
+            let a = 0
+            let b = 0
+            let c = 0
+            let d = 0
+            let e = 0
+            let f = 0
+            read a
+            f += 2
+            read b
+            e = a
+            read c
+            d = f
+            d *= 3
+        ").unwrap();
+        let options = Options { reorder_statements: true, ..Options::default() };
+        let reordered = trans_with_options(&ir, &options).unwrap().code;
+        let original = trans(&ir).unwrap();
+        let seeks = |code: &str| code.chars().filter(|&c| c == '<' || c == '>').count();
+        let run = |code: &str| {
+            let mut vm = VM::new();
+            vm.set_input("xyz");
+            vm.run(code).unwrap();
+            vm.mem()[..6].to_vec()
+        };
+
+        assert!(seeks(&reordered) < seeks(&original));
+        assert_eq!(run(&reordered), [b'x', b'y', b'z', 6, b'x', 2]);
+        assert_eq!(run(&reordered), run(&original));
+    }
+
+    #[test]
+    fn builtins() {
+        let ir = IR::parse_str("
+            let x = max(3, 7)
+            let y = min(x, 4) + abs_diff(2, x)
+            let z = read_num()
+            print_num(z + 1)
+        ").unwrap();
+        let code = trans(&ir).unwrap();
+        let mut vm = VM::new();
+        vm.set_input("41\n");
+        vm.run(code).unwrap();
+
+        assert_eq!(vm.mem()[..3], [7, 9, 41]);
+        assert_eq!(vm.output(), b"42");
+    }
+
+    #[test]
+    fn unknown_function() {
+        let ir = IR::parse_str("let x = foo(1)").unwrap();
+        let err = trans(&ir).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown function 'foo'");
+
+        let ir = IR::parse_str("let x = max(1)").unwrap();
+        assert!(trans(&ir).is_err());
+    }
+
+    #[test]
+    fn cell_width() {
+        let ir = IR::parse_str("
+            #cells u16
+            let x = 1
+        ").unwrap();
+        assert!(trans(&ir).is_err());
+    }
+}